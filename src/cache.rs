@@ -1,33 +1,541 @@
 use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Debug,
     fs::{File, OpenOptions},
     io::{BufReader, BufWriter},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
-use anyhow::anyhow;
-use log::info;
-use serde_json::{Map, Value};
+use anyhow::{anyhow, bail, Context as _};
+use chrono::{DateTime, Utc};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
 
-pub(super) fn load_cache(cache_file_path: &Path) -> anyhow::Result<Map<String, Value>> {
-    if cache_file_path.exists() {
-        let fd = File::open(cache_file_path)?;
+use crate::config::CacheStorageKind;
+
+/// Version of the format of cache entries, written in the header of the cache.
+/// Bump it and add a step to `migrate` when the format changes.
+///
+/// - 0: `null` for pages without OGP info, or `{type, title, url, thumb_url, description?}` (no header)
+/// - 1: `CacheEntry`
+const SCHEMA_VERSION: u64 = 1;
+
+/// OGP info of each URL.
+pub(crate) type OgpEntries = BTreeMap<String, CacheEntry>;
+
+/// A result of fetching OGP info of a URL.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct CacheEntry {
+    /// `None` for ones from version 0, whose age is unknown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fetched_at: Option<DateTime<Utc>>,
+    /// HTTP status code. `None` if the page could not be fetched.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u32>,
+    #[serde(flatten)]
+    pub result: FetchResult,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FetchResult {
+    Info(Box<OgpInfo>),
+    /// the page does not have enough OGP info, or could not be fetched
+    Error(String),
+}
+
+/// Open Graph data shown in a card.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct OgpInfo {
+    #[serde(rename = "type")]
+    pub og_type: String,
+    pub title: String,
+    pub url: String,
+    /// URL of the first image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumb_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// file name of the downloaded thumbnail in `ogp-thumbnails`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<String>,
+    /// URL of the favicon
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon_url: Option<String>,
+    /// file name of the downloaded favicon in `ogp-thumbnails`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    /// whether the page lacks some of the required Open Graph data (type, title, url and image),
+    /// and they are taken from `<title>`, Twitter card tags and so on, or left out
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub fallback: bool,
+    /// other properties such as `site_name` and `locale`, without `og:`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<OgpObject>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub videos: Vec<OgpObject>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audios: Vec<OgpObject>,
+}
+
+/// `og:image`, `og:video` or `og:audio` with its properties such as `width` and `type`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct OgpObject {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, String>,
+}
+
+impl CacheEntry {
+    pub fn info(&self) -> Option<&OgpInfo> {
+        match &self.result {
+            FetchResult::Info(info) => Some(info),
+            FetchResult::Error(_) => None,
+        }
+    }
+
+    pub fn is_failure(&self) -> bool {
+        self.info().is_none()
+    }
+}
+
+impl OgpInfo {
+    /// Names of the downloaded thumbnail and favicon in `ogp-thumbnails`
+    pub fn saved_files(&self) -> impl Iterator<Item = &String> {
+        self.thumbnail.iter().chain(&self.icon)
+    }
+}
+
+/// Converts a cache entry written in `version` into the current version.
+fn migrate(version: u64, mut entry: Value) -> anyhow::Result<Value> {
+    if version > SCHEMA_VERSION {
+        bail!("cache version {version} is newer than this blogen ({SCHEMA_VERSION})");
+    }
+    if version < 1 {
+        entry = match entry {
+            Value::Null => json!({ "error": "no OGP info" }),
+            Value::Object(mut info) => {
+                let mut migrated = Map::new();
+                for key in ["fetched_at", "status"] {
+                    if let Some(v) = info.remove(key) {
+                        migrated.insert(key.to_string(), v);
+                    }
+                }
+                match info.remove("error") {
+                    Some(error) => migrated.insert("error".to_string(), error),
+                    None => migrated.insert("info".to_string(), Value::Object(info)),
+                };
+                Value::Object(migrated)
+            }
+            entry => entry,
+        };
+    }
+
+    Ok(entry)
+}
+
+/// Where the OGP cache is stored. Entries are passed as JSON in the current version.
+pub(crate) trait CacheStorage: Debug {
+    fn path(&self) -> &Path;
+    fn exists(&self) -> bool;
+    /// Loads entries, migrating them from the version in the header.
+    fn load(&self) -> anyhow::Result<Map<String, Value>>;
+    /// Saves entries with the header of the current version.
+    fn save(&self, cache: &Map<String, Value>) -> anyhow::Result<()>;
+    /// Removes the storage after migrating from it.
+    fn remove(&self) -> anyhow::Result<()>;
+}
+
+/// A single zstd-compressed JSON file, `{"version": .., "entries": {URL: entry}}`.
+/// Ones of version 0 have only the entries.
+#[derive(Debug)]
+pub(crate) struct ZstdFile {
+    pub path: PathBuf,
+}
+
+impl CacheStorage for ZstdFile {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    fn load(&self) -> anyhow::Result<Map<String, Value>> {
+        let fd = File::open(&self.path)?;
         let reader = BufReader::new(fd);
-        serde_json::from_reader(zstd::stream::decode_all(reader)?.as_slice())
-            .map_err(|e| anyhow!(e))
-    } else {
-        info!("Cache file({cache_file_path:?}) does not exist. ignoring...",);
-        Ok(Map::new())
+        let mut file: Map<String, Value> =
+            serde_json::from_reader(zstd::stream::decode_all(reader)?.as_slice())?;
+        let (version, entries) = match file.remove("version") {
+            Some(version) => (
+                version
+                    .as_u64()
+                    .ok_or_else(|| anyhow!("Invalid version {version}"))?,
+                match file.remove("entries") {
+                    Some(Value::Object(entries)) => entries,
+                    _ => bail!("entries do not exist"),
+                },
+            ),
+            None => (0, file),
+        };
+
+        entries
+            .into_iter()
+            .map(|(url, entry)| {
+                let entry = migrate(version, entry).with_context(|| format!("in {url}"))?;
+                Ok((url, entry))
+            })
+            .collect()
+    }
+
+    fn save(&self, cache: &Map<String, Value>) -> anyhow::Result<()> {
+        let cache_file_fd = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&self.path)?;
+        let writer = BufWriter::new(cache_file_fd);
+        let file = json!({ "version": SCHEMA_VERSION, "entries": cache });
+        zstd::stream::copy_encode(serde_json::to_vec(&file)?.as_slice(), writer, 0)?;
+
+        Ok(())
     }
+
+    fn remove(&self) -> anyhow::Result<()> {
+        Ok(std::fs::remove_file(&self.path)?)
+    }
+}
+
+/// A directory with a pretty-printed JSON file for each URL, named after the hash of the URL.
+/// Each file has its own version, `{"version": .., "url": .., "entry": ..}`.
+/// Only files of changed entries are rewritten, so that diffs stay small.
+#[derive(Debug)]
+pub(crate) struct JsonDirectory {
+    pub path: PathBuf,
 }
 
-pub(super) fn save_cache(cache_file_path: &Path, cache: &Map<String, Value>) -> anyhow::Result<()> {
-    let cache_file_fd = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open(cache_file_path)?;
-    let writer = BufWriter::new(cache_file_fd);
-    zstd::stream::copy_encode(serde_json::to_vec(&cache)?.as_slice(), writer, 0)?;
+impl JsonDirectory {
+    fn file_name(url: &str) -> String {
+        format!("{:x}.json", Sha256::digest(url))
+    }
+}
+
+impl CacheStorage for JsonDirectory {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn exists(&self) -> bool {
+        self.path.is_dir()
+    }
+
+    fn load(&self) -> anyhow::Result<Map<String, Value>> {
+        let mut cache = Map::new();
+        for entry in std::fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let content = std::fs::read_to_string(&path)
+                .with_context(|| format!("while reading {path:?}"))?;
+            let mut file: Map<String, Value> = serde_json::from_str(&content)
+                .with_context(|| format!("while parsing {path:?}"))?;
+            let (Some(Value::String(url)), Some(entry)) =
+                (file.remove("url"), file.remove("entry"))
+            else {
+                bail!("{path:?} does not have url or entry");
+            };
+            let version = file.remove("version").map_or(Some(0), |v| v.as_u64());
+            let Some(version) = version else {
+                bail!("{path:?} has invalid version");
+            };
+            let entry = migrate(version, entry).with_context(|| format!("in {path:?}"))?;
+            cache.insert(url, entry);
+        }
 
-    Ok(())
+        Ok(cache)
+    }
+
+    fn save(&self, cache: &Map<String, Value>) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.path)
+            .with_context(|| format!("while making {:?}", self.path))?;
+        let mut file_names = HashSet::new();
+        for (url, entry) in cache.iter() {
+            let file_name = Self::file_name(url);
+            let path = self.path.join(&file_name);
+            let content = serde_json::to_string_pretty(&json!({
+                "version": SCHEMA_VERSION,
+                "url": url,
+                "entry": entry,
+            }))? + "\n";
+            if std::fs::read_to_string(&path).ok().as_ref() != Some(&content) {
+                debug!("Writing cache of {url} into {path:?}");
+                std::fs::write(&path, content)
+                    .with_context(|| format!("while writing {path:?}"))?;
+            }
+            file_names.insert(file_name);
+        }
+        // entries deleted from the cache
+        for entry in std::fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if file_name.ends_with(".json") && !file_names.contains(&file_name) {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn remove(&self) -> anyhow::Result<()> {
+        Ok(std::fs::remove_dir_all(&self.path)?)
+    }
+}
+
+/// The OGP cache in the configured storage, migrated from the other one if it does not exist yet.
+#[derive(Debug)]
+pub(crate) struct OgpCache {
+    storage: Box<dyn CacheStorage>,
+    others: Vec<Box<dyn CacheStorage>>,
+}
+
+impl OgpCache {
+    /// Storage named `cache.json.zst` or `ogp-cache` in the current directory.
+    pub fn new(kind: CacheStorageKind) -> Self {
+        let zstd: Box<dyn CacheStorage> = Box::new(ZstdFile {
+            path: PathBuf::from("cache.json.zst"),
+        });
+        let directory: Box<dyn CacheStorage> = Box::new(JsonDirectory {
+            path: PathBuf::from("ogp-cache"),
+        });
+        match kind {
+            CacheStorageKind::Zstd => Self::with_storage(zstd, vec![directory]),
+            CacheStorageKind::Directory => Self::with_storage(directory, vec![zstd]),
+        }
+    }
+
+    pub fn with_storage(
+        storage: Box<dyn CacheStorage>,
+        others: Vec<Box<dyn CacheStorage>>,
+    ) -> Self {
+        Self { storage, others }
+    }
+
+    /// Loads entries. Corrupt ones are reported all at once, to be fixed or deleted.
+    pub fn load(&self) -> anyhow::Result<OgpEntries> {
+        let mut entries = OgpEntries::new();
+        let mut errors = vec![];
+        for (url, entry) in self.load_raw()? {
+            match serde_json::from_value(entry) {
+                Ok(entry) => {
+                    entries.insert(url, entry);
+                }
+                Err(e) => errors.push(format!("  {url}: {e}")),
+            }
+        }
+        if !errors.is_empty() {
+            bail!(
+                "Corrupt entries in the cache {:?}:\n{}\nDelete them with `blogen cache delete URL...`.",
+                self.storage.path(),
+                errors.join("\n")
+            );
+        }
+
+        Ok(entries)
+    }
+
+    pub fn save(&self, entries: &OgpEntries) -> anyhow::Result<()> {
+        let raw = entries
+            .iter()
+            .map(|(url, entry)| Ok((url.clone(), serde_json::to_value(entry)?)))
+            .collect::<anyhow::Result<_>>()?;
+        self.save_raw(&raw)
+    }
+
+    /// Loads entries as JSON, including corrupt ones.
+    pub fn load_raw(&self) -> anyhow::Result<Map<String, Value>> {
+        if self.storage.exists() {
+            return self
+                .storage
+                .load()
+                .with_context(|| format!("while loading cache from {:?}", self.storage.path()));
+        }
+        match self.others.iter().find(|s| s.exists()) {
+            Some(other) => {
+                info!(
+                    "Migrating cache from {:?} to {:?}",
+                    other.path(),
+                    self.storage.path()
+                );
+                other
+                    .load()
+                    .with_context(|| format!("while loading cache from {:?}", other.path()))
+            }
+            None => {
+                info!(
+                    "Cache({:?}) does not exist. ignoring...",
+                    self.storage.path()
+                );
+                Ok(Map::new())
+            }
+        }
+    }
+
+    /// Saves `cache`, and removes the other storages which it was migrated from.
+    pub fn save_raw(&self, cache: &Map<String, Value>) -> anyhow::Result<()> {
+        self.storage
+            .save(cache)
+            .with_context(|| format!("while saving cache into {:?}", self.storage.path()))?;
+        for other in self.others.iter().filter(|s| s.exists()) {
+            info!(
+                "Removing {:?}, migrated to {:?}",
+                other.path(),
+                self.storage.path()
+            );
+            other.remove()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{failure, temp_dir};
+
+    fn entries() -> OgpEntries {
+        OgpEntries::from([
+            (
+                "https://example.com/a".to_string(),
+                CacheEntry {
+                    fetched_at: Some("2026-01-01T00:00:00Z".parse().unwrap()),
+                    status: Some(200),
+                    result: FetchResult::Info(Box::new(OgpInfo {
+                        og_type: "website".to_string(),
+                        title: "A".to_string(),
+                        url: "https://example.com/a".to_string(),
+                        thumb_url: Some("https://example.com/a.png".to_string()),
+                        ..OgpInfo::default()
+                    })),
+                },
+            ),
+            (
+                "https://example.com/b".to_string(),
+                failure(Some("2026-01-01T00:00:00Z")),
+            ),
+        ])
+    }
+
+    fn cache(dir: &Path, storage: CacheStorageKind) -> OgpCache {
+        let zstd = Box::new(ZstdFile {
+            path: dir.join("cache.json.zst"),
+        });
+        let directory = Box::new(JsonDirectory {
+            path: dir.join("ogp-cache"),
+        });
+        match storage {
+            CacheStorageKind::Zstd => OgpCache::with_storage(zstd, vec![directory]),
+            CacheStorageKind::Directory => OgpCache::with_storage(directory, vec![zstd]),
+        }
+    }
+
+    #[test]
+    fn saves_and_loads_directory() {
+        let dir = temp_dir("directory");
+        let cache = cache(&dir, CacheStorageKind::Directory);
+        let mut entries = entries();
+        cache.save(&entries).unwrap();
+        assert_eq!(cache.load().unwrap(), entries);
+
+        entries.remove("https://example.com/b");
+        cache.save(&entries).unwrap();
+        assert_eq!(std::fs::read_dir(dir.join("ogp-cache")).unwrap().count(), 1);
+        assert_eq!(cache.load().unwrap(), entries);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn migrates_between_storages() {
+        let dir = temp_dir("migration");
+        cache(&dir, CacheStorageKind::Zstd)
+            .save(&entries())
+            .unwrap();
+
+        let directory = cache(&dir, CacheStorageKind::Directory);
+        let loaded = directory.load().unwrap();
+        assert_eq!(loaded, entries());
+        assert!(dir.join("cache.json.zst").exists());
+        directory.save(&loaded).unwrap();
+        assert!(!dir.join("cache.json.zst").exists());
+
+        let zstd = cache(&dir, CacheStorageKind::Zstd);
+        assert_eq!(zstd.load().unwrap(), entries());
+        zstd.save(&entries()).unwrap();
+        assert!(!dir.join("ogp-cache").exists());
+        assert_eq!(zstd.load().unwrap(), entries());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn migrates_version_0() {
+        let dir = temp_dir("version-0");
+        let cache = cache(&dir, CacheStorageKind::Zstd);
+        let v0 = json!({
+            "https://example.com/a": {
+                "type": "website",
+                "title": "A",
+                "url": "https://example.com/a",
+                "thumb_url": "https://example.com/a.png",
+                "fetched_at": "2026-01-01T00:00:00Z",
+                "status": 200,
+            },
+            "https://example.com/b": null,
+        });
+        std::fs::write(
+            dir.join("cache.json.zst"),
+            zstd::encode_all(v0.to_string().as_bytes(), 0).unwrap(),
+        )
+        .unwrap();
+
+        let loaded = cache.load().unwrap();
+        assert_eq!(
+            loaded["https://example.com/a"],
+            entries()["https://example.com/a"]
+        );
+        let b = &loaded["https://example.com/b"];
+        assert!(b.is_failure());
+        assert_eq!(b.fetched_at, None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_corrupt_entries() {
+        let dir = temp_dir("corrupt");
+        let cache = cache(&dir, CacheStorageKind::Zstd);
+        let mut raw = Map::new();
+        raw.insert(
+            "https://example.com/a".to_string(),
+            json!({ "info": { "type": "website" } }),
+        );
+        raw.insert("https://example.com/b".to_string(), json!({}));
+        cache.save_raw(&raw).unwrap();
+
+        let error = cache.load().unwrap_err().to_string();
+        assert!(error.contains("https://example.com/a: missing field `title`"));
+        assert!(error.contains("https://example.com/b"));
+        assert_eq!(cache.load_raw().unwrap(), raw);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
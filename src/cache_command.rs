@@ -0,0 +1,291 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::bail;
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use log::warn;
+
+use crate::{
+    cache::{CacheEntry, FetchResult, OgpCache, OgpEntries, OgpInfo},
+    config::{Config, OgpConfig},
+    generator::{fetch_all, needs_refetch, referenced_urls},
+    ogp::HttpFetcher,
+};
+
+/// `blogen cache`: inspects and maintains the OGP cache.
+pub(super) fn command() -> Command {
+    let urls = Arg::new("urls")
+        .help("URLs of entries")
+        .required(true)
+        .num_args(1..);
+
+    Command::new("cache")
+        .about("Inspect and maintain the OGP cache")
+        .subcommand_required(true)
+        .subcommands([
+            Command::new("list")
+                .about("List cached URLs with their fetch time, and titles or errors")
+                .args([
+                    Arg::new("failed")
+                        .long("failed")
+                        .help("Only entries without a title or failed to fetch, including null ones from older versions")
+                        .action(ArgAction::SetTrue),
+                    Arg::new("stale")
+                        .long("stale")
+                        .help("Only entries to be fetched again on the next build")
+                        .action(ArgAction::SetTrue),
+                ]),
+            Command::new("show")
+                .about("Show the entry of a URL")
+                .arg(Arg::new("url").help("URL of the entry").required(true)),
+            Command::new("delete")
+                .about("Delete entries, so that they are fetched again on the next build")
+                .arg(urls.clone()),
+            Command::new("refetch")
+                .about("Fetch OGP info of URLs now")
+                .arg(urls),
+            Command::new("prune")
+                .about("Delete entries not referenced by any article, and thumbnails and favicons of deleted ones")
+                .arg(
+                    Arg::new("article_dir")
+                        .help("Directory path of articles")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .default_value("posts"),
+                ),
+            Command::new("export")
+                .about("Write the cache as readable JSON")
+                .arg(
+                    Arg::new("path")
+                        .help("Path of the JSON file. Written to stdout if omitted.")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                ),
+        ])
+}
+
+pub(super) fn run(
+    matches: &ArgMatches,
+    config: &Config,
+    storage: &OgpCache,
+    thumbnail_dir: &Path,
+) -> anyhow::Result<()> {
+    // these work on entries as JSON, so that corrupt ones can be inspected and deleted
+    match matches.subcommand().unwrap() {
+        ("show", matches) => {
+            let url: &String = matches.get_one("url").unwrap();
+            let cache = storage.load_raw()?;
+            let Some(entry) = cache.get(url) else {
+                bail!("{url} is not in the cache");
+            };
+            println!("{}", serde_json::to_string_pretty(entry)?);
+            return Ok(());
+        }
+        ("export", matches) => {
+            let json = serde_json::to_string_pretty(&storage.load_raw()?)?;
+            match matches.get_one::<PathBuf>("path") {
+                Some(path) => std::fs::write(path, json + "\n")?,
+                None => println!("{json}"),
+            }
+            return Ok(());
+        }
+        ("delete", matches) => {
+            let mut cache = storage.load_raw()?;
+            for url in matches.get_many::<String>("urls").unwrap() {
+                if cache.remove(url).is_none() {
+                    warn!("{url} is not in the cache");
+                }
+            }
+            return storage.save_raw(&cache);
+        }
+        _ => {}
+    }
+
+    let mut cache = storage.load()?;
+    match matches.subcommand().unwrap() {
+        ("list", matches) => {
+            for (url, entry) in filter_entries(
+                &cache,
+                matches.get_flag("failed"),
+                matches.get_flag("stale"),
+                &config.ogp,
+                Utc::now(),
+            ) {
+                println!("{url}\t{}", summary(entry));
+            }
+            return Ok(());
+        }
+        ("refetch", matches) => {
+            let urls = matches
+                .get_many::<String>("urls")
+                .unwrap()
+                .cloned()
+                .collect();
+            let fetcher = HttpFetcher {
+                timeout: Duration::from_secs(config.ogp.request_timeout),
+            };
+            let mutex = Mutex::new(cache);
+            fetch_all(urls, &mutex, &fetcher, &config.ogp, thumbnail_dir);
+            cache = mutex.into_inner().unwrap();
+        }
+        ("prune", matches) => {
+            let article_dir: &PathBuf = matches.get_one("article_dir").unwrap();
+            if !article_dir.is_dir() {
+                bail!("article_dir must be a directory.");
+            }
+            for url in prune(&mut cache, &referenced_urls(article_dir, &config.articles)?) {
+                println!("{url}");
+            }
+            for path in prune_thumbnails(&cache, thumbnail_dir)? {
+                println!("{}", path.display());
+            }
+        }
+        _ => unreachable!(),
+    }
+
+    storage.save(&cache)
+}
+
+/// Entries matching all of the given filters.
+fn filter_entries<'a>(
+    cache: &'a OgpEntries,
+    failed: bool,
+    stale: bool,
+    config: &OgpConfig,
+    now: DateTime<Utc>,
+) -> Vec<(&'a String, &'a CacheEntry)> {
+    cache
+        .iter()
+        .filter(|(_, c)| !failed || c.is_failure())
+        .filter(|(_, c)| !stale || needs_refetch(c, config, now))
+        .collect()
+}
+
+/// Fetch time and the title or error of the entry, separated by a tab.
+fn summary(c: &CacheEntry) -> String {
+    let fetched_at = c.fetched_at.map_or("-".to_string(), |t| {
+        t.to_rfc3339_opts(SecondsFormat::Secs, true)
+    });
+    let detail = match &c.result {
+        FetchResult::Info(info) => info.title.clone(),
+        FetchResult::Error(error) => format!("error: {error}"),
+    };
+
+    format!("{fetched_at}\t{detail}")
+}
+
+/// Removes entries whose URLs are not in `referenced`, and returns the URLs.
+fn prune(cache: &mut OgpEntries, referenced: &HashSet<String>) -> Vec<String> {
+    let unreferenced: Vec<_> = cache
+        .keys()
+        .filter(|url| !referenced.contains(*url))
+        .cloned()
+        .collect();
+    for url in unreferenced.iter() {
+        cache.remove(url);
+    }
+
+    unreferenced
+}
+
+/// Removes thumbnails and favicons in `thumbnail_dir` not recorded in `cache`, and returns their paths.
+fn prune_thumbnails(cache: &OgpEntries, thumbnail_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if !thumbnail_dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let recorded: HashSet<_> = cache
+        .values()
+        .filter_map(CacheEntry::info)
+        .flat_map(OgpInfo::saved_files)
+        .map(String::as_str)
+        .collect();
+    let mut removed = vec![];
+    for entry in std::fs::read_dir(thumbnail_dir)? {
+        let entry = entry?;
+        if !recorded.contains(&*entry.file_name().to_string_lossy()) {
+            std::fs::remove_file(entry.path())?;
+            removed.push(entry.path());
+        }
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn cache() -> OgpEntries {
+        serde_json::from_value(json!({
+            "https://example.com/ok": {
+                "fetched_at": "2025-12-31T00:00:00Z",
+                "info": {
+                    "type": "website",
+                    "title": "OK",
+                    "url": "https://example.com/ok",
+                    "thumb_url": "https://example.com/t.png",
+                },
+            },
+            "https://example.com/null": { "error": "no OGP info" },
+            "https://example.com/failed": {
+                "fetched_at": "2025-12-31T00:00:00Z",
+                "error": "HTTP status 404",
+            },
+        }))
+        .unwrap()
+    }
+
+    fn urls<'a>(entries: Vec<(&'a String, &CacheEntry)>) -> Vec<&'a str> {
+        entries.into_iter().map(|(url, _)| url.as_str()).collect()
+    }
+
+    #[test]
+    fn filters_failed_and_stale_entries() {
+        let cache = cache();
+        let config = OgpConfig::default();
+        let now = "2026-01-01T00:00:00Z".parse().unwrap();
+
+        assert_eq!(filter_entries(&cache, false, false, &config, now).len(), 3);
+        assert_eq!(
+            urls(filter_entries(&cache, true, false, &config, now)),
+            ["https://example.com/failed", "https://example.com/null"]
+        );
+        assert_eq!(
+            urls(filter_entries(&cache, false, true, &config, now)),
+            ["https://example.com/null"]
+        );
+    }
+
+    #[test]
+    fn summarizes_entries() {
+        let cache = cache();
+        assert_eq!(
+            summary(&cache["https://example.com/ok"]),
+            "2025-12-31T00:00:00Z\tOK"
+        );
+        assert_eq!(
+            summary(&cache["https://example.com/failed"]),
+            "2025-12-31T00:00:00Z\terror: HTTP status 404"
+        );
+        assert_eq!(
+            summary(&cache["https://example.com/null"]),
+            "-\terror: no OGP info"
+        );
+    }
+
+    #[test]
+    fn prunes_unreferenced_entries() {
+        let mut cache = cache();
+        let referenced = HashSet::from(["https://example.com/ok".to_string()]);
+
+        assert_eq!(
+            prune(&mut cache, &referenced),
+            ["https://example.com/failed", "https://example.com/null"]
+        );
+        assert_eq!(cache.keys().collect::<Vec<_>>(), ["https://example.com/ok"]);
+    }
+}
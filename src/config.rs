@@ -0,0 +1,281 @@
+use std::path::Path;
+
+use anyhow::Context as _;
+use log::info;
+use serde::Deserialize;
+
+/// Site-wide settings read from `blogen.toml`. Every field has a default, so the file is optional.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub articles: ArticlesConfig,
+    pub highlight: HighlightConfig,
+    pub heading: HeadingConfig,
+    pub markdown: MarkdownConfig,
+    pub admonition: AdmonitionConfig,
+    pub links: LinksConfig,
+    pub images: ImagesConfig,
+    pub ogp: OgpConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ArticlesConfig {
+    /// files with these extensions are rendered as articles, and others are copied as they are
+    pub extensions: Vec<String>,
+    /// patterns of paths to skip, in the same syntax as `.blogenignore` (and `.gitignore`)
+    pub exclude: Vec<String>,
+    /// whether files and directories whose names start with `.` are included
+    pub include_hidden: bool,
+    /// whether symbolic links are followed. They are skipped otherwise.
+    pub follow_symlinks: bool,
+}
+
+impl Default for ArticlesConfig {
+    fn default() -> Self {
+        Self {
+            extensions: vec!["md".to_string(), "markdown".to_string()],
+            exclude: vec!["README.md".to_string()],
+            include_hidden: false,
+            follow_symlinks: false,
+        }
+    }
+}
+
+impl ArticlesConfig {
+    pub fn is_article(&self, path: &Path) -> bool {
+        path.extension()
+            .is_some_and(|ext| self.extensions.iter().any(|e| ext == e.as_str()))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct HighlightConfig {
+    /// name of a theme bundled with syntect, or a `.tmTheme` file path
+    pub theme: String,
+    pub style: HighlightStyle,
+}
+
+impl Default for HighlightConfig {
+    fn default() -> Self {
+        Self {
+            theme: "InspiredGitHub".to_string(),
+            style: HighlightStyle::Class,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HighlightStyle {
+    /// emit `<span class="...">` and generate a stylesheet from the theme
+    Class,
+    /// emit `<span style="...">` with colors of the theme
+    Inline,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct HeadingConfig {
+    /// append `#` link to each heading
+    pub permalink: bool,
+    /// levels to demote headings in article bodies by
+    pub offset: HeadingOffset,
+    /// level of the shallowest heading in a body when `offset = "auto"`.
+    /// `article.hbs` uses `<h2>` for the title, so body starts from `<h3>`.
+    pub top_level: usize,
+    /// number of headings needed to show table of contents, unless `toc` is given in the header
+    pub toc_min_headings: usize,
+}
+
+impl Default for HeadingConfig {
+    fn default() -> Self {
+        Self {
+            permalink: false,
+            offset: HeadingOffset::Auto,
+            top_level: 3,
+            toc_min_headings: 3,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HeadingOffset {
+    /// demote so that the shallowest heading has `top_level`
+    Auto,
+    #[serde(untagged)]
+    Fixed(usize),
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct MarkdownConfig {
+    /// how a line break in a paragraph is rendered. can be overridden by `line_break` in the header
+    pub line_break: LineBreak,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LineBreak {
+    /// `<br>`
+    #[default]
+    Hard,
+    /// a space, as in plain Markdown
+    Space,
+    /// nothing between CJK characters, a space otherwise
+    Cjk,
+}
+
+/// Titles of `> [!NOTE]` style callouts
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AdmonitionConfig {
+    pub note: String,
+    pub tip: String,
+    pub important: String,
+    pub warning: String,
+    pub caution: String,
+}
+
+impl Default for AdmonitionConfig {
+    fn default() -> Self {
+        Self {
+            note: "メモ".to_string(),
+            tip: "ヒント".to_string(),
+            important: "重要".to_string(),
+            warning: "注意".to_string(),
+            caution: "警告".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LinksConfig {
+    /// fail the build on a broken internal link, instead of warning.
+    /// Off by default, since links which did not resolve before only produced dead links.
+    pub strict: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ImagesConfig {
+    /// convert local images into `<picture>` with resized ones
+    pub enabled: bool,
+    /// widths of resized images in pixels. Ones not narrower than the original are omitted,
+    /// and the original width is always added.
+    pub widths: Vec<u32>,
+    /// formats of resized images, in order of preference.
+    /// AVIF is smaller than WebP, but takes far longer to encode.
+    pub formats: Vec<ImageFormat>,
+    /// 1-100
+    pub quality: u8,
+    /// `sizes` attribute of `<source>`
+    pub sizes: String,
+    /// make a gallery of images put together in a paragraph
+    pub gallery: bool,
+}
+
+impl Default for ImagesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            widths: vec![480, 960, 1920],
+            formats: vec![ImageFormat::Webp],
+            quality: 75,
+            sizes: "(max-width: 960px) 100vw, 960px".to_string(),
+            gallery: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ImageFormat {
+    Avif,
+    Webp,
+}
+
+/// Fetching Open Graph data for link cards
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct OgpConfig {
+    /// use only the cache, without network access (also set by `--offline`)
+    pub offline: bool,
+    /// number of requests running at once
+    pub concurrency: usize,
+    /// number of requests running at once to the same host
+    pub per_host_concurrency: usize,
+    /// seconds between requests to the same host
+    pub per_host_interval: u64,
+    /// seconds of each request
+    pub request_timeout: u64,
+    /// seconds of the whole fetching. URLs left are shown as plain links, and fetched in the next build.
+    pub timeout: u64,
+    /// days until OGP info is fetched again
+    pub ttl: u64,
+    /// days until a page without OGP info (or failed to fetch) is fetched again
+    pub failure_ttl: u64,
+    /// where the cache is stored. It is migrated from the other one automatically.
+    pub storage: CacheStorageKind,
+    /// whether thumbnails are downloaded into `ogp-thumbnails` and served from the site, instead of hotlinked
+    pub thumbnails: bool,
+    /// height in pixels which thumbnails are shrunk to (cards are 5em high, so twice that for high-DPI screens)
+    pub thumbnail_height: u32,
+    /// data a page needs to be shown as a card, instead of a plain link. Checked on each build, without refetching.
+    pub card_requires: CardRequirement,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CacheStorageKind {
+    /// `cache.json.zst`, a single compressed file
+    Zstd,
+    /// `ogp-cache/`, a JSON file for each URL, which is easy to diff and merge
+    Directory,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CardRequirement {
+    /// all of `og:type`, `og:title`, `og:url` and `og:image`
+    Ogp,
+    /// a title and an image, from Open Graph or Twitter card tags
+    Image,
+    /// a title, and a description or an image
+    Summary,
+    /// only a title, which may be `<title>`
+    Title,
+}
+
+impl Default for OgpConfig {
+    fn default() -> Self {
+        Self {
+            offline: false,
+            concurrency: 8,
+            per_host_concurrency: 1,
+            per_host_interval: 10,
+            request_timeout: 10,
+            timeout: 300,
+            ttl: 365,
+            failure_ttl: 7,
+            storage: CacheStorageKind::Zstd,
+            thumbnails: true,
+            thumbnail_height: 160,
+            card_requires: CardRequirement::Summary,
+        }
+    }
+}
+
+pub(super) fn load_config(config_file_path: &Path) -> anyhow::Result<Config> {
+    if config_file_path.exists() {
+        let content = std::fs::read_to_string(config_file_path)
+            .with_context(|| format!("while opening {:?}", config_file_path))?;
+        toml::from_str(&content).with_context(|| format!("while parsing {:?}", config_file_path))
+    } else {
+        info!("Config file({config_file_path:?}) does not exist. using defaults...");
+        Ok(Config::default())
+    }
+}
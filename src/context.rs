@@ -1,9 +1,14 @@
-use serde_json::{Map, Value};
 use std::{
+    collections::HashMap,
     path::PathBuf,
     sync::{Mutex, OnceLock},
 };
 
+use crate::{
+    cache::OgpEntries, config::Config, highlighter::Highlighter, ogp::OgpFetcher,
+    renderer::ShortcodeArgs,
+};
+
 #[derive(Debug)]
 pub(crate) struct Context {
     pub article_dir: PathBuf,
@@ -13,33 +18,27 @@ pub(crate) struct Context {
     pub blog_name: String,
     pub blog_url: String,
 
+    pub config: Config,
     pub handlebars: handlebars::Handlebars<'static>,
-    pub opengraph_cache: Mutex<Map<String, Value>>,
+    pub shortcodes: handlebars::Handlebars<'static>,
+    /// arguments declared by shortcode templates, by their names
+    pub shortcode_args: HashMap<String, ShortcodeArgs>,
+    pub highlighter: Highlighter,
+    /// directory to cache rendered diagrams, next to the OGP cache
+    pub diagram_cache_dir: PathBuf,
+    /// directory to cache resized images
+    pub image_cache_dir: PathBuf,
+    /// directory to save thumbnails of link cards, next to the OGP cache
+    pub thumbnail_dir: PathBuf,
+    pub opengraph_cache: Mutex<OgpEntries>,
+    pub ogp_fetcher: Box<dyn OgpFetcher>,
 }
 
 static CONTEXT: OnceLock<Context> = OnceLock::new();
 
 impl Context {
-    pub fn init(
-        article_dir: PathBuf,
-        out_dir: PathBuf,
-        public_dir: PathBuf,
-        blog_name: String,
-        blog_url: String,
-        handlebars: handlebars::Handlebars<'static>,
-        opengraph_cache: Mutex<Map<String, Value>>,
-    ) {
-        CONTEXT
-            .set(Self {
-                article_dir,
-                out_dir,
-                public_dir,
-                blog_name,
-                blog_url,
-                handlebars,
-                opengraph_cache,
-            })
-            .unwrap();
+    pub fn init(context: Self) {
+        CONTEXT.set(context).unwrap();
     }
 
     pub fn instance() -> &'static Context {
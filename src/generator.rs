@@ -1,9 +1,9 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     fs::{create_dir_all, Metadata as FileMetadata, OpenOptions},
     io::BufWriter,
     iter::FromIterator,
-    path::PathBuf,
+    path::{Path, PathBuf},
     rc::Rc,
     time::SystemTime,
 };
@@ -14,28 +14,150 @@ use atom_syndication::{EntryBuilder, FeedBuilder, LinkBuilder};
 use chrono::{Local, NaiveDate};
 
 use fs_extra::dir::CopyOptions;
-use log::debug;
-use pulldown_cmark::{html, Options, Parser};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use log::{debug, warn};
+use pulldown_cmark::{html, Options, Parser, TextMergeWithOffset};
+use serde::{
+    de::{value::StrDeserializer, IntoDeserializer},
+    Deserialize,
+};
 
 use self::{
+    card::copy_thumbnails,
     data::{ArticleMetadata, ArticlePageData, ListPageData},
-    utils::{gen_parser_event_iterator, sort_article},
+    heading::{build_toc, heading_offset, Headings, Slugger},
+    image::wrap_images,
+    link::ArticleIndex,
+    prefetch::{autolinks, prefetch, uncached},
+    shortcode::{expand_shortcodes, placeholder},
+    utils::{gen_parser_event_iterator, resolve_soft_breaks, sort_article},
+};
+use crate::{
+    config::{ArticlesConfig, LineBreak},
+    context::Context,
 };
-use crate::context::Context;
 
+pub(crate) use self::{card::needs_refetch, prefetch::fetch_all};
+
+mod card;
+mod code_block;
 mod data;
+mod diagram;
+mod heading;
+mod image;
+mod link;
+mod prefetch;
+mod ruby;
+mod shortcode;
 mod utils;
 
+/// Rules to skip paths in `article_dir`, from `.blogenignore` in it and `articles.exclude` of the config.
+fn load_ignore_rules(article_dir: &Path, config: &ArticlesConfig) -> anyhow::Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(article_dir);
+    let ignore_file = article_dir.join(".blogenignore");
+    if ignore_file.exists() {
+        if let Some(e) = builder.add(&ignore_file) {
+            return Err(e).with_context(|| format!("while reading {ignore_file:?}"));
+        }
+    }
+    for pattern in config.exclude.iter() {
+        builder
+            .add_line(None, pattern)
+            .with_context(|| format!("Invalid exclude pattern {pattern:?}"))?;
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Paths (relative to `article_dir`) and metadata of files and directories in `article_dir`, breadth-first.
+/// Hidden, ignored and (unless configured) symbolic-linked ones are skipped.
+fn walk_article_dir(
+    article_dir: &Path,
+    config: &ArticlesConfig,
+) -> anyhow::Result<Vec<(PathBuf, FileMetadata)>> {
+    let mut result = vec![];
+    let ignore_rules = load_ignore_rules(article_dir, config)?;
+    let mut visited_directories = HashSet::from([article_dir.canonicalize()?]); // symlinkによるループ避け
+    let mut q = VecDeque::new(); // article_dirからの相対パスを入れるqueue
+    q.push_back(PathBuf::new());
+    while let Some(current_directory_relpath) = q.pop_front() {
+        // relpathはarticle_dirからの相対パス、abspathはarticle_dirを含めたパス
+        // abspathは厳密にはabsではないかもしれない
+        let current_directory_abspath = article_dir.join(&current_directory_relpath);
+
+        for entry in std::fs::read_dir(current_directory_abspath)? {
+            let entry = entry?;
+            let entry_relpath = current_directory_relpath.join(entry.file_name());
+
+            if !config.include_hidden && entry.file_name().as_encoded_bytes()[0] == b'.' {
+                debug!("Skipping {entry_relpath:?}: hidden");
+                continue;
+            }
+            let meta = if entry.file_type()?.is_symlink() {
+                if !config.follow_symlinks {
+                    debug!("Skipping {entry_relpath:?}: symbolic link");
+                    continue;
+                }
+                match std::fs::metadata(entry.path()) {
+                    Ok(meta) => meta,
+                    Err(e) => {
+                        warn!("Skipping {entry_relpath:?}: broken symbolic link ({e})");
+                        continue;
+                    }
+                }
+            } else {
+                entry.metadata()?
+            };
+            if ignore_rules
+                .matched_path_or_any_parents(entry.path(), meta.is_dir())
+                .is_ignore()
+            {
+                debug!("Skipping {entry_relpath:?}: ignored");
+                continue;
+            }
+
+            if meta.is_dir() {
+                if !visited_directories.insert(entry.path().canonicalize()?) {
+                    debug!("Skipping {entry_relpath:?}: already visited");
+                    continue;
+                }
+                q.push_back(entry_relpath.clone());
+            }
+            result.push((entry_relpath, meta));
+        }
+    }
+
+    Ok(result)
+}
+
+/// URLs of autolinks in all articles in `article_dir`, which are shown as cards.
+pub(crate) fn referenced_urls(
+    article_dir: &Path,
+    config: &ArticlesConfig,
+) -> anyhow::Result<HashSet<String>> {
+    let mut urls = HashSet::new();
+    for (relpath, meta) in walk_article_dir(article_dir, config)? {
+        if meta.is_file() && config.is_article(&relpath) {
+            let metadata = preprocess_article(article_dir, relpath.clone(), meta)
+                .with_context(|| format!("while preprocessing {relpath:?}"))?;
+            urls.extend(autolinks(&metadata));
+        }
+    }
+
+    Ok(urls)
+}
+
 fn preprocess_article(
+    article_dir: &Path,
     file_relpath: PathBuf,
     file_meta: FileMetadata,
 ) -> anyhow::Result<ArticleMetadata> {
-    let ctx = Context::instance();
     let mut metadata = ArticleMetadata::new(file_meta);
     metadata.relpath = file_relpath.with_extension("");
     metadata.is_page = true;
 
-    let source_abspath = ctx.article_dir.join(metadata.relpath.with_extension("md"));
+    let source_abspath = article_dir.join(&file_relpath);
+    metadata.source_relpath = file_relpath;
     let content = std::fs::read_to_string(&source_abspath)
         .with_context(|| format!("while opening {:?}", source_abspath))?;
     // parsing pandoc-style metadata block
@@ -44,6 +166,10 @@ fn preprocess_article(
         .build()
         .unwrap();
     metadata.body = if let Some(caps) = header_pattern.captures(&content) {
+        metadata.body_line = content[..caps.get(2).unwrap().start()]
+            .matches('\n')
+            .count()
+            + 1;
         let header = &caps[1];
         for line in header.split('\n') {
             if line.is_empty() {
@@ -56,7 +182,7 @@ fn preprocess_article(
 
             let name = s[0].trim();
             let value = s[1].trim();
-            // currently, title and tag are supported
+            // currently, title, tag, date, toc and line_break are supported
             match name {
                 "title" => {
                     metadata.title = value.to_string();
@@ -64,6 +190,16 @@ fn preprocess_article(
                 "tag" => {
                     metadata.tags = value.split(',').map(|s| s.to_string()).collect();
                 }
+                "toc" => {
+                    metadata.toc = Some(value.parse().context("Invalid toc value")?);
+                }
+                "line_break" => {
+                    let deserializer: StrDeserializer<serde::de::value::Error> =
+                        value.into_deserializer();
+                    metadata.line_break = Some(
+                        LineBreak::deserialize(deserializer).context("Invalid line_break value")?,
+                    );
+                }
                 "date" => {
                     metadata.date = Some(
                         NaiveDate::parse_from_str(value, "%Y-%m-%d")
@@ -82,17 +218,70 @@ fn preprocess_article(
     Ok(metadata)
 }
 
+fn markdown_options() -> Options {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_HEADING_ATTRIBUTES);
+    options.insert(Options::ENABLE_GFM);
+    options
+}
+
+/// Renders `body`, a part of `metadata.body` starting at `body_offset`, into HTML.
+/// `(level, id, title)` of the headings in it are returned too.
+/// `slugger` is shared with Markdown in shortcodes, so that ids are unique in the whole article.
+fn render_markdown(
+    metadata: &ArticleMetadata,
+    body: &str,
+    body_offset: usize,
+    slugger: &mut Slugger,
+) -> anyhow::Result<(String, Headings)> {
+    let ctx = Context::instance();
+    let options = markdown_options();
+    let expanded = expand_shortcodes(metadata, body, body_offset, slugger)?;
+
+    let heading_offset = heading_offset(&expanded.body, options);
+    let mut headings = vec![];
+    // each event comes with its offset in `metadata.body`, to locate errors
+    let mut events: Vec<_> =
+        TextMergeWithOffset::new(Parser::new_ext(&expanded.body, options).into_offset_iter())
+            .map(|(event, range)| (event, body_offset + expanded.original_offset(range.start)))
+            .collect();
+    resolve_soft_breaks(
+        &mut events,
+        metadata
+            .line_break
+            .unwrap_or(ctx.config.markdown.line_break),
+    );
+    let events = wrap_images(metadata, events)
+        .into_iter()
+        .map(gen_parser_event_iterator(
+            metadata,
+            heading_offset,
+            &mut headings,
+            slugger,
+        ))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut body_html = String::new();
+    html::push_html(&mut body_html, events.into_iter());
+    for (i, shortcode) in expanded.shortcodes.iter().enumerate() {
+        body_html = body_html.replace(&placeholder(i), shortcode);
+    }
+
+    Ok((body_html, headings))
+}
+
 fn generate_article(
     metadata: &ArticleMetadata,
     prev_meta: Option<&ArticleMetadata>,
     next_meta: Option<&ArticleMetadata>,
+    backlinks: Vec<&ArticleMetadata>,
 ) -> anyhow::Result<()> {
     let ctx = Context::instance();
-    let mut options = Options::empty();
-    options.insert(Options::ENABLE_STRIKETHROUGH);
-    options.insert(Options::ENABLE_TABLES);
-
-    let parser = Parser::new_ext(&metadata.body, options).map(gen_parser_event_iterator());
+    let (body_html, headings) =
+        render_markdown(metadata, &metadata.body, 0, &mut Slugger::default())
+            .with_context(|| format!("while rendering {:?}", metadata.relpath))?;
 
     let out_abspath = ctx.out_dir.join(metadata.relpath.with_extension("html"));
 
@@ -104,16 +293,23 @@ fn generate_article(
         .open(&out_abspath)
         .with_context(|| format!("while opening file {:?}", out_abspath))?;
 
-    let mut body_html = String::new();
-    html::push_html(&mut body_html, parser);
-
     let date_of_starting_use_giscus = NaiveDate::from_ymd_opt(2025, 1, 27).unwrap();
     let data = ArticlePageData {
         blog_name: &ctx.blog_name,
+        highlight_stylesheet: ctx.highlighter.has_stylesheet(),
         body: body_html,
         meta: metadata,
         prev_meta,
         next_meta,
+        toc: if metadata
+            .toc
+            .unwrap_or(headings.len() >= ctx.config.heading.toc_min_headings)
+        {
+            build_toc(headings)
+        } else {
+            vec![]
+        },
+        backlinks,
         use_giscus: date_of_starting_use_giscus <= metadata.date.unwrap_or(NaiveDate::MAX),
     };
     ctx.handlebars
@@ -135,59 +331,110 @@ pub(crate) fn generate() -> anyhow::Result<()> {
         .overwrite(true);
     fs_extra::dir::copy(&ctx.public_dir, ctx.out_dir.join(&ctx.public_dir), &cp_opts)?;
 
+    // stylesheet for highlighted code blocks
+    if let Some(css) = ctx.highlighter.stylesheet()? {
+        std::fs::write(ctx.out_dir.join(&ctx.public_dir).join("highlight.css"), css)
+            .context("while writing highlight.css")?;
+    }
+
     // master data
     let mut articles = vec![];
+    let mut assets = vec![];
 
     // subdirectory data
     let mut directory_entries: HashMap<PathBuf, Vec<Rc<ArticleMetadata>>> = HashMap::new();
     let mut tags: HashMap<String, Vec<Rc<ArticleMetadata>>> = HashMap::new();
 
     // traversing `article_dir`
-    let mut q = VecDeque::new(); // article_dirからの相対パスを入れるqueue
-    q.push_back(PathBuf::new());
-    while let Some(current_directory_relpath) = q.pop_front() {
-        // relpathはarticle_dirからの相対パス、abspathはarticle_dirを含めたパス
-        // abspathは厳密にはabsではないかもしれない
-        let current_directory_abspath = ctx.article_dir.join(&current_directory_relpath);
-
-        let entries_in_current_directory = directory_entries
-            .entry(current_directory_relpath.clone())
-            .or_default();
-
-        for entry in std::fs::read_dir(current_directory_abspath)? {
-            let entry = entry?;
-            let meta = entry.metadata()?;
-            let entry_relpath = current_directory_relpath.join(entry.file_name());
-
-            if meta.is_dir() {
-                q.push_back(entry_relpath.clone());
-
-                let mut meta = ArticleMetadata::new(meta);
-                meta.title = entry.file_name().to_string_lossy().into_owned();
-                meta.relpath = entry_relpath;
-
-                (*entries_in_current_directory).push(Rc::new(meta));
-            } else if meta.is_file() {
-                let article_meta =
-                    Rc::new(preprocess_article(entry_relpath, meta).with_context(|| {
-                        format!(
-                            "while preprocessing {:?}",
-                            &current_directory_relpath.join(entry.file_name())
-                        )
-                    })?);
-                for tag in article_meta.tags.iter() {
-                    let tag_entries = tags.entry(tag.to_string()).or_default();
-                    (*tag_entries).push(Rc::clone(&article_meta));
-                    // (*tag_entries).push(articles.len());
-                }
-                (*entries_in_current_directory).push(Rc::clone(&article_meta));
-                articles.push(article_meta.clone());
+    directory_entries.insert(PathBuf::new(), vec![]);
+    for (entry_relpath, meta) in walk_article_dir(&ctx.article_dir, &ctx.config.articles)? {
+        let entry_abspath = ctx.article_dir.join(&entry_relpath);
+        let parent_relpath = entry_relpath.parent().unwrap().to_path_buf();
+
+        if meta.is_dir() {
+            directory_entries.entry(entry_relpath.clone()).or_default();
+
+            let mut meta = ArticleMetadata::new(meta);
+            meta.title = entry_relpath
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned();
+            meta.relpath = entry_relpath;
+
+            directory_entries
+                .entry(parent_relpath)
+                .or_default()
+                .push(Rc::new(meta));
+        } else if meta.is_file() && !ctx.config.articles.is_article(&entry_relpath) {
+            // assets placed next to articles
+            let out_abspath = ctx.out_dir.join(&entry_relpath);
+            create_dir_all(out_abspath.parent().unwrap())?;
+            std::fs::copy(&entry_abspath, &out_abspath)
+                .with_context(|| format!("while copying {entry_relpath:?}"))?;
+            assets.push(entry_relpath);
+        } else if meta.is_file() {
+            let article_meta = Rc::new(
+                preprocess_article(&ctx.article_dir, entry_relpath.clone(), meta)
+                    .with_context(|| format!("while preprocessing {entry_relpath:?}"))?,
+            );
+            for tag in article_meta.tags.iter() {
+                let tag_entries = tags.entry(tag.to_string()).or_default();
+                (*tag_entries).push(Rc::clone(&article_meta));
             }
+            directory_entries
+                .entry(parent_relpath)
+                .or_default()
+                .push(Rc::clone(&article_meta));
+            articles.push(article_meta.clone());
         }
     }
 
     articles.sort_by(sort_article);
 
+    ArticleIndex::init(
+        articles
+            .iter()
+            .map(|a| (a.relpath.clone(), a.title.clone())),
+        directory_entries.keys().cloned(),
+        tags.keys().cloned(),
+        assets,
+    );
+
+    debug!("building link graph");
+    let mut backlinks: HashMap<PathBuf, Vec<&ArticleMetadata>> = HashMap::new();
+    for article in articles.iter() {
+        for linked in ArticleIndex::instance().linked_articles(article) {
+            backlinks.entry(linked).or_default().push(article);
+        }
+    }
+
+    let urls: Vec<_> = articles.iter().flat_map(|a| autolinks(a)).collect();
+    let uncached_urls = if ctx.config.ogp.offline {
+        uncached(urls.iter().cloned(), &ctx.opengraph_cache)
+    } else {
+        debug!("fetching OGP info");
+        if ctx.config.ogp.thumbnails {
+            // committed with the cache even if empty, by the workflow
+            create_dir_all(&ctx.thumbnail_dir)
+                .with_context(|| format!("while making {:?}", ctx.thumbnail_dir))?;
+        }
+        prefetch(
+            urls.iter().cloned(),
+            &ctx.opengraph_cache,
+            ctx.ogp_fetcher.as_ref(),
+            &ctx.config.ogp,
+            &ctx.thumbnail_dir,
+        );
+        vec![]
+    };
+    copy_thumbnails(
+        &urls,
+        &ctx.opengraph_cache,
+        &ctx.thumbnail_dir,
+        &ctx.out_dir,
+    )?;
+
     debug!("generating articles");
     for (i, article) in articles.iter().enumerate() {
         let prev = if i == articles.len() {
@@ -200,7 +447,12 @@ pub(crate) fn generate() -> anyhow::Result<()> {
         } else {
             articles.get(i - 1).map(|a| a.as_ref())
         };
-        generate_article(article, prev, next)?;
+        generate_article(
+            article,
+            prev,
+            next,
+            backlinks.remove(&article.relpath).unwrap_or_default(),
+        )?;
     }
 
     debug!("generating feed");
@@ -281,6 +533,7 @@ pub(crate) fn generate() -> anyhow::Result<()> {
 
             let index_data = ListPageData {
                 blog_name: &ctx.blog_name,
+                highlight_stylesheet: ctx.highlighter.has_stylesheet(),
                 title: "index".to_string(),
                 relpath: PathBuf::from("/"),
                 is_page: false,
@@ -299,6 +552,7 @@ pub(crate) fn generate() -> anyhow::Result<()> {
         } else {
             let list_data = ListPageData {
                 blog_name: &ctx.blog_name,
+                highlight_stylesheet: ctx.highlighter.has_stylesheet(),
                 title: name,
                 relpath: directory_relpath,
                 is_page: false,
@@ -328,6 +582,7 @@ pub(crate) fn generate() -> anyhow::Result<()> {
 
         let list_data = ListPageData {
             blog_name: &ctx.blog_name,
+            highlight_stylesheet: ctx.highlighter.has_stylesheet(),
             title: format!("タグ: {}", tag),
             relpath: tag_relpath,
             is_page: true,
@@ -344,5 +599,65 @@ pub(crate) fn generate() -> anyhow::Result<()> {
             .with_context(|| format!("while generating for tag {:?}", tag))?;
     }
 
+    if !uncached_urls.is_empty() {
+        warn!(
+            "Offline: {} URLs without cache were shown as plain links:",
+            uncached_urls.len()
+        );
+        for url in uncached_urls {
+            warn!("  {url}");
+        }
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    #[test]
+    fn skips_ignored_hidden_and_symlinked_paths() {
+        let dir = temp_dir("walk");
+        for file in [
+            "a.md",
+            "README.md",
+            ".hidden/b.md",
+            "drafts/c.md",
+            "sub/d.md",
+            "sub/e.tmp",
+        ] {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        std::fs::write(dir.join(".blogenignore"), "drafts/\n").unwrap();
+        std::os::unix::fs::symlink(dir.join("a.md"), dir.join("link.md")).unwrap();
+        let config = ArticlesConfig {
+            exclude: vec!["README.md".to_string(), "*.tmp".to_string()],
+            ..ArticlesConfig::default()
+        };
+
+        let walk = |config: &ArticlesConfig| {
+            let mut relpaths: Vec<_> = walk_article_dir(&dir, config)
+                .unwrap()
+                .into_iter()
+                .map(|(relpath, _)| relpath)
+                .collect();
+            relpaths.sort();
+            relpaths
+        };
+        assert_eq!(
+            walk(&config),
+            ["a.md", "sub", "sub/d.md"].map(PathBuf::from)
+        );
+        let config = ArticlesConfig {
+            follow_symlinks: true,
+            ..config
+        };
+        assert!(walk(&config).contains(&PathBuf::from("link.md")));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
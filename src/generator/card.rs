@@ -0,0 +1,760 @@
+use std::{
+    fs::create_dir_all,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context as _;
+use chrono::{DateTime, SubsecRound, TimeDelta, Utc};
+use handlebars::Handlebars;
+use image::imageops::FilterType;
+use log::{debug, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use url::Url;
+use webpage::OpengraphObject;
+
+use crate::{
+    cache::{CacheEntry, FetchResult, OgpEntries, OgpInfo, OgpObject},
+    config::{CardRequirement, ImageFormat, OgpConfig},
+    ogp::OgpFetcher,
+};
+
+/// directory in `out_dir` where thumbnails are copied
+const THUMBNAIL_OUT_DIR: &str = "ogp-thumbnails";
+
+const THUMBNAIL_FORMAT: ImageFormat = ImageFormat::Webp;
+const THUMBNAIL_QUALITY: u8 = 75;
+/// height in pixels which favicons are shrunk to (shown at 1em of the small URL line)
+const ICON_HEIGHT: u32 = 32;
+
+/// Data passed to `og_card.hbs`: the cached Open Graph data, with the link and the images to show.
+#[derive(Serialize, Debug)]
+struct CardData<'a> {
+    href: &'a str,
+    /// URL of the downloaded thumbnail, or the original one
+    image: Option<String>,
+    /// URL of the downloaded favicon, or the original one
+    icon_src: Option<String>,
+    #[serde(flatten)]
+    info: &'a OgpInfo,
+}
+
+/// Name of the template for `og_type`: `og_card_video.movie`, `og_card_video` or `og_card`, whichever exists first.
+fn card_template(handlebars: &Handlebars, og_type: &str) -> String {
+    let main_type = og_type.split('.').next().unwrap_or_default();
+    [og_type, main_type]
+        .iter()
+        .filter(|t| !t.is_empty())
+        .map(|t| format!("og_card_{t}"))
+        .find(|name| handlebars.has_template(name))
+        .unwrap_or_else(|| "og_card".to_string())
+}
+
+pub(super) fn render_card(
+    handlebars: &Handlebars,
+    href: &str,
+    info: &OgpInfo,
+    thumbnail_dir: &Path,
+) -> anyhow::Result<String> {
+    let template = card_template(handlebars, &info.og_type);
+    let data = CardData {
+        href,
+        image: saved_or_original(
+            info.thumbnail.as_deref(),
+            info.thumb_url.as_deref(),
+            thumbnail_dir,
+        ),
+        icon_src: saved_or_original(
+            info.icon.as_deref(),
+            info.icon_url.as_deref(),
+            thumbnail_dir,
+        ),
+        info,
+    };
+    handlebars
+        .render(&template, &data)
+        .with_context(|| format!("while rendering {template}"))
+}
+
+/// Card for an autolink to `url` from `cache`, which is filled by `prefetch` beforehand.
+/// `None` if the page does not have data required by `config.card_requires`, or is not cached.
+pub(super) fn link_card(
+    url: &str,
+    cache: &Mutex<OgpEntries>,
+    config: &OgpConfig,
+    thumbnail_dir: &Path,
+    handlebars: &Handlebars,
+) -> Option<String> {
+    debug!("Getting cache of {url}...");
+    match cache.lock().unwrap().get(url).map(CacheEntry::info) {
+        Some(Some(info)) if satisfies(info, config.card_requires) => {
+            render_card(handlebars, url, info, thumbnail_dir)
+                .inspect_err(|e| warn!("Showing {url} as a plain link: {e:#}"))
+                .ok()
+        }
+        Some(Some(_)) => {
+            debug!("but it did not have enough info for a card.");
+            None
+        }
+        Some(None) => {
+            debug!("but there seemed to be no ogp info.");
+            None
+        }
+        None => {
+            debug!("but it was not fetched.");
+            None
+        }
+    }
+}
+
+fn satisfies(info: &OgpInfo, requirement: CardRequirement) -> bool {
+    match requirement {
+        CardRequirement::Ogp => !info.fallback,
+        CardRequirement::Image => info.thumb_url.is_some(),
+        CardRequirement::Summary => info.description.is_some() || info.thumb_url.is_some(),
+        CardRequirement::Title => true,
+    }
+}
+
+/// The downloaded image copied by `copy_thumbnails` if any, or the original one otherwise.
+fn saved_or_original(
+    name: Option<&str>,
+    original: Option<&str>,
+    thumbnail_dir: &Path,
+) -> Option<String> {
+    match name {
+        Some(name) if thumbnail_dir.join(name).exists() => {
+            Some(format!("/{THUMBNAIL_OUT_DIR}/{name}"))
+        }
+        _ => original.map(String::from),
+    }
+}
+
+/// Images in a card, which are downloaded into `thumbnail_dir` if configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum CardImage {
+    Thumbnail,
+    Icon,
+}
+
+impl CardImage {
+    pub const ALL: [Self; 2] = [Self::Thumbnail, Self::Icon];
+
+    /// height in pixels which the image is shrunk to
+    pub fn height(self, config: &OgpConfig) -> u32 {
+        match self {
+            Self::Thumbnail => config.thumbnail_height,
+            Self::Icon => ICON_HEIGHT,
+        }
+    }
+
+    /// The original URL and the file name of the downloaded one
+    pub fn fields(self, info: &OgpInfo) -> (Option<&str>, Option<&str>) {
+        match self {
+            Self::Thumbnail => (info.thumb_url.as_deref(), info.thumbnail.as_deref()),
+            Self::Icon => (info.icon_url.as_deref(), info.icon.as_deref()),
+        }
+    }
+
+    pub fn fields_mut(self, info: &mut OgpInfo) -> (&mut Option<String>, &mut Option<String>) {
+        match self {
+            Self::Thumbnail => (&mut info.thumb_url, &mut info.thumbnail),
+            Self::Icon => (&mut info.icon_url, &mut info.icon),
+        }
+    }
+
+    /// URL of the image which is not downloaded into `thumbnail_dir` yet,
+    /// including ones from older versions and ones deleted from the directory.
+    pub fn missing<'a>(self, info: &'a OgpInfo, thumbnail_dir: &Path) -> Option<&'a str> {
+        match self.fields(info) {
+            (_, Some(name)) if thumbnail_dir.join(name).exists() => None,
+            (url, _) => url,
+        }
+    }
+}
+
+/// Copies downloaded thumbnails and favicons of `urls` into `out_dir`.
+pub(super) fn copy_thumbnails<'a>(
+    urls: impl IntoIterator<Item = &'a String>,
+    cache: &Mutex<OgpEntries>,
+    thumbnail_dir: &Path,
+    out_dir: &Path,
+) -> anyhow::Result<()> {
+    let out_dir = out_dir.join(THUMBNAIL_OUT_DIR);
+    let cache = cache.lock().unwrap();
+    let names = urls
+        .into_iter()
+        .filter_map(|url| cache.get(url)?.info())
+        .flat_map(OgpInfo::saved_files);
+    for name in names {
+        let path = thumbnail_dir.join(name);
+        let out_path = out_dir.join(name);
+        if !path.exists() || out_path.exists() {
+            continue;
+        }
+        create_dir_all(&out_dir).with_context(|| format!("while making {out_dir:?}"))?;
+        std::fs::copy(&path, &out_path)
+            .with_context(|| format!("while copying {path:?} to {out_path:?}"))?;
+    }
+
+    Ok(())
+}
+
+/// Whether the cache entry should be fetched again, because it is older than the TTL.
+/// Successful entries from older versions, whose age is unknown, are kept.
+pub(crate) fn needs_refetch(c: &CacheEntry, config: &OgpConfig, now: DateTime<Utc>) -> bool {
+    let ttl = if c.is_failure() {
+        config.failure_ttl
+    } else {
+        config.ttl
+    };
+    match c.fetched_at {
+        Some(fetched_at) => now - fetched_at >= TimeDelta::days(ttl as i64),
+        None => c.is_failure(),
+    }
+}
+
+/// Fetches OGP info of `url` as a cache entry. The thumbnail and the favicon are downloaded separately by `save_images`.
+/// The entry is an error if the page does not have any title, or could not be fetched.
+pub(super) fn fetch_entry(
+    url: &str,
+    fetcher: &dyn OgpFetcher,
+    config: &OgpConfig,
+    now: DateTime<Utc>,
+) -> CacheEntry {
+    let fetched_at = Some(now.trunc_subsecs(0));
+    let page = match fetcher.fetch(url) {
+        Ok(page) => page,
+        Err(e) => {
+            debug!("failed to fetch {url}: {e}");
+            return CacheEntry {
+                fetched_at,
+                status: None,
+                result: FetchResult::Error(e.to_string()),
+            };
+        }
+    };
+    let status = Some(page.status);
+    if page.status >= 400 {
+        debug!("{url} returned {}", page.status);
+        return CacheEntry {
+            fetched_at,
+            status,
+            result: FetchResult::Error(format!("HTTP status {}", page.status)),
+        };
+    }
+
+    // OGP Requirements: title, type, url, image.
+    // Missing ones are taken from other tags, and only a title is needed for a card in the end.
+    let html = page.html;
+    let mut og = html.opengraph;
+    let meta = |name: &str| html.meta.get(name).filter(|s| !s.is_empty()).cloned();
+    let fallback = og.og_type.is_empty()
+        || !og.properties.contains_key("title")
+        || !og.properties.contains_key("url")
+        || og.images.is_empty();
+    let title = og
+        .properties
+        .remove("title")
+        .or_else(|| meta("twitter:title"))
+        .or_else(|| {
+            html.title
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+        });
+    let result = match title {
+        Some(title) => {
+            let thumb_url = og
+                .images
+                .first()
+                .map(|image| image.url.clone())
+                .or_else(|| meta("twitter:image"))
+                .or_else(|| meta("twitter:image:src"));
+            // `/favicon.ico` may not exist, so it is used only if it is downloaded
+            let icon_url = page
+                .icon
+                .or_else(|| config.thumbnails.then(|| favicon_ico(&page.url)).flatten());
+            FetchResult::Info(Box::new(OgpInfo {
+                og_type: match og.og_type.as_str() {
+                    "" => "website".to_string(),
+                    _ => og.og_type,
+                },
+                title,
+                url: og
+                    .properties
+                    .remove("url")
+                    .or(html.url)
+                    .unwrap_or_else(|| url.to_string()),
+                thumb_url,
+                description: og
+                    .properties
+                    .remove("description")
+                    .or_else(|| meta("twitter:description"))
+                    .or(html.description.filter(|d| !d.is_empty())),
+                thumbnail: None,
+                icon_url,
+                icon: None,
+                fallback,
+                properties: og.properties.into_iter().collect(),
+                images: og.images.into_iter().map(ogp_object).collect(),
+                videos: og.videos.into_iter().map(ogp_object).collect(),
+                audios: og.audios.into_iter().map(ogp_object).collect(),
+            }))
+        }
+        None => {
+            debug!("there was no title in {url}.");
+            FetchResult::Error("no title".to_string())
+        }
+    };
+
+    CacheEntry {
+        fetched_at,
+        status,
+        result,
+    }
+}
+
+/// `/favicon.ico` of the site of `url`, which browsers look for when no icon is declared.
+fn favicon_ico(url: &str) -> Option<String> {
+    Url::parse(url)
+        .ok()?
+        .join("/favicon.ico")
+        .ok()
+        .map(String::from)
+}
+
+fn ogp_object(object: OpengraphObject) -> OgpObject {
+    OgpObject {
+        url: object.url,
+        properties: object.properties.into_iter().collect(),
+    }
+}
+
+/// Downloads the image at `thumb_url`, shrinks it to `height`, and saves it into `thumbnail_dir`.
+/// The file is named after the hash of its content, and the name is returned.
+pub(super) fn save_thumbnail(
+    thumb_url: &str,
+    fetcher: &dyn OgpFetcher,
+    thumbnail_dir: &Path,
+    height: u32,
+) -> anyhow::Result<String> {
+    let image = image::load_from_memory(&fetcher.fetch_image(thumb_url)?)?;
+    let image = if image.height() > height {
+        image.resize(u32::MAX, height, FilterType::Lanczos3)
+    } else {
+        image
+    };
+    let encoded = THUMBNAIL_FORMAT.encode(&image, THUMBNAIL_QUALITY)?;
+
+    let name = format!(
+        "{:x}.{}",
+        Sha256::digest(&encoded),
+        THUMBNAIL_FORMAT.extension()
+    );
+    let path: PathBuf = thumbnail_dir.join(&name);
+    if !path.exists() {
+        create_dir_all(thumbnail_dir).with_context(|| format!("while making {thumbnail_dir:?}"))?;
+        std::fs::write(&path, encoded).with_context(|| format!("while writing {path:?}"))?;
+    }
+
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ogp::StubFetcher,
+        test_utils::{failure, ogp_config, png, temp_dir, NO_THUMBNAILS},
+    };
+
+    const URL: &str = "https://example.com/page";
+
+    const PAGE: &str = r#"<html><head>
+        <meta property="og:type" content="article">
+        <meta property="og:title" content="Example title">
+        <meta property="og:url" content="https://example.com/page">
+        <meta property="og:image" content="https://example.com/thumb.png">
+        <meta property="og:description" content="Example description">
+        </head><body></body></html>"#;
+
+    const NO_TEMPLATES: &str = "/nonexistent";
+
+    fn handlebars(template_dir: &str) -> Handlebars<'static> {
+        let mut handlebars = Handlebars::new();
+        crate::renderer::register_card_templates(&mut handlebars, Path::new(template_dir)).unwrap();
+        handlebars
+    }
+
+    fn requested(fetcher: &StubFetcher) -> Vec<String> {
+        fetcher.requested.lock().unwrap().clone()
+    }
+
+    fn now() -> DateTime<Utc> {
+        "2026-01-01T00:00:00Z".parse().unwrap()
+    }
+
+    fn success(fetched_at: Option<&str>) -> CacheEntry {
+        CacheEntry {
+            fetched_at: fetched_at.map(|t| t.parse().unwrap()),
+            status: Some(200),
+            result: FetchResult::Info(Box::new(OgpInfo {
+                og_type: "website".to_string(),
+                title: "Cached title".to_string(),
+                url: URL.to_string(),
+                thumb_url: Some("https://example.com/cached.png".to_string()),
+                ..OgpInfo::default()
+            })),
+        }
+    }
+
+    #[test]
+    fn renders_card_from_cache() {
+        let cache = Mutex::new(OgpEntries::from([(URL.to_string(), success(None))]));
+
+        let card = link_card(
+            URL,
+            &cache,
+            &OgpConfig::default(),
+            Path::new(NO_THUMBNAILS),
+            &handlebars(NO_TEMPLATES),
+        )
+        .unwrap();
+        assert!(card.contains("Cached title"));
+        assert!(card.contains("og-type-website"));
+        assert!(card.contains(r#"src="https://example.com/cached.png""#));
+    }
+
+    #[test]
+    fn failure_or_missing_cache_is_plain_link() {
+        let cache = Mutex::new(OgpEntries::from([(
+            URL.to_string(),
+            failure(Some("2025-12-31T00:00:00Z")),
+        )]));
+        assert_eq!(
+            link_card(
+                URL,
+                &cache,
+                &OgpConfig::default(),
+                Path::new(NO_THUMBNAILS),
+                &handlebars(NO_TEMPLATES)
+            ),
+            None
+        );
+        assert_eq!(
+            link_card(
+                "https://example.com/other",
+                &cache,
+                &OgpConfig::default(),
+                Path::new(NO_THUMBNAILS),
+                &handlebars(NO_TEMPLATES)
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn refetches_entries_older_than_ttl() {
+        let config = OgpConfig {
+            ttl: 30,
+            failure_ttl: 7,
+            ..OgpConfig::default()
+        };
+
+        assert!(!needs_refetch(
+            &success(Some("2025-12-10T00:00:00Z")),
+            &config,
+            now()
+        ));
+        assert!(needs_refetch(
+            &success(Some("2025-11-30T00:00:00Z")),
+            &config,
+            now()
+        ));
+        assert!(!needs_refetch(
+            &failure(Some("2025-12-30T00:00:00Z")),
+            &config,
+            now()
+        ));
+        assert!(needs_refetch(
+            &failure(Some("2025-12-20T00:00:00Z")),
+            &config,
+            now()
+        ));
+        // from older versions
+        assert!(needs_refetch(&failure(None), &config, now()));
+        assert!(!needs_refetch(&success(None), &config, now()));
+    }
+
+    #[test]
+    fn fetches_entry() {
+        let fetcher = StubFetcher::default().with_page(URL, PAGE);
+
+        let entry = fetch_entry(URL, &fetcher, &ogp_config(), now());
+        let info = entry.info().unwrap();
+        assert_eq!(info.title, "Example title");
+        assert_eq!(info.description.as_deref(), Some("Example description"));
+        assert_eq!(
+            info.thumb_url.as_deref(),
+            Some("https://example.com/thumb.png")
+        );
+        assert!(!info.fallback);
+        assert_eq!(entry.fetched_at, Some(now()));
+        assert_eq!(entry.status, Some(200));
+        assert_eq!(requested(&fetcher), [URL]);
+
+        let cache = Mutex::new(OgpEntries::from([(URL.to_string(), entry)]));
+        assert!(link_card(
+            URL,
+            &cache,
+            &OgpConfig::default(),
+            Path::new(NO_THUMBNAILS),
+            &handlebars(NO_TEMPLATES)
+        )
+        .unwrap()
+        .contains("Example title"));
+    }
+
+    #[test]
+    fn fetches_failure_for_page_without_title() {
+        let fetcher = StubFetcher::default().with_page(
+            URL,
+            r#"<html><head><title> </title><meta name="description" content="D"></head></html>"#,
+        );
+        let entry = fetch_entry(URL, &fetcher, &ogp_config(), now());
+        assert_eq!(entry.status, Some(200));
+        assert_eq!(entry.result, FetchResult::Error("no title".to_string()));
+    }
+
+    #[test]
+    fn falls_back_to_other_tags() {
+        let fetcher = StubFetcher::default().with_page(
+            URL,
+            r#"<html><head>
+            <title>Page title</title>
+            <meta name="description" content="Meta description">
+            <meta name="twitter:image" content="https://example.com/twitter.png">
+            <link rel="icon" href="/icon.png">
+            </head></html>"#,
+        );
+        let entry = fetch_entry(URL, &fetcher, &ogp_config(), now());
+        let info = entry.info().unwrap();
+        assert_eq!(info.og_type, "website");
+        assert_eq!(info.title, "Page title");
+        assert_eq!(info.url, URL);
+        assert_eq!(info.description.as_deref(), Some("Meta description"));
+        assert_eq!(
+            info.thumb_url.as_deref(),
+            Some("https://example.com/twitter.png")
+        );
+        assert_eq!(
+            info.icon_url.as_deref(),
+            Some("https://example.com/icon.png")
+        );
+        assert!(info.fallback);
+
+        // Open Graph data comes first
+        let fetcher = StubFetcher::default().with_page(
+            URL,
+            r#"<html><head>
+            <title>Page title</title>
+            <meta name="twitter:title" content="Twitter title">
+            <meta property="og:description" content="OG description">
+            </head></html>"#,
+        );
+        let entry = fetch_entry(URL, &fetcher, &ogp_config(), now());
+        let info = entry.info().unwrap();
+        assert_eq!(info.title, "Twitter title");
+        assert_eq!(info.description.as_deref(), Some("OG description"));
+        assert_eq!(info.thumb_url, None);
+        assert_eq!(info.icon_url, None);
+    }
+
+    #[test]
+    fn shows_card_only_with_required_data() {
+        let info = |description: Option<&str>, thumb_url: Option<&str>, fallback| OgpInfo {
+            og_type: "website".to_string(),
+            title: "Title".to_string(),
+            url: URL.to_string(),
+            thumb_url: thumb_url.map(String::from),
+            description: description.map(String::from),
+            fallback,
+            ..OgpInfo::default()
+        };
+        let complete = info(None, Some("https://example.com/t.png"), false);
+        let with_image = info(None, Some("https://example.com/t.png"), true);
+        let with_description = info(Some("Description"), None, true);
+        let title_only = info(None, None, true);
+
+        let shown = |requirement| {
+            [&complete, &with_image, &with_description, &title_only]
+                .map(|info| satisfies(info, requirement))
+        };
+        assert_eq!(shown(CardRequirement::Ogp), [true, false, false, false]);
+        assert_eq!(shown(CardRequirement::Image), [true, true, false, false]);
+        assert_eq!(shown(CardRequirement::Summary), [true, true, true, false]);
+        assert_eq!(shown(CardRequirement::Title), [true, true, true, true]);
+
+        let cache = Mutex::new(OgpEntries::from([(
+            URL.to_string(),
+            CacheEntry {
+                fetched_at: Some(now()),
+                status: Some(200),
+                result: FetchResult::Info(Box::new(title_only)),
+            },
+        )]));
+        let card = |card_requires| {
+            let config = OgpConfig {
+                card_requires,
+                ..OgpConfig::default()
+            };
+            link_card(
+                URL,
+                &cache,
+                &config,
+                Path::new(NO_THUMBNAILS),
+                &handlebars(NO_TEMPLATES),
+            )
+        };
+        assert_eq!(card(CardRequirement::Summary), None);
+        assert!(!card(CardRequirement::Title).unwrap().contains("<img"));
+    }
+
+    #[test]
+    fn fetches_failure_for_error_status() {
+        let fetcher = StubFetcher::default().with_status(URL, 404, PAGE);
+        let entry = fetch_entry(URL, &fetcher, &ogp_config(), now());
+        assert!(entry.is_failure());
+        assert_eq!(entry.status, Some(404));
+    }
+
+    #[test]
+    fn fetches_failure_for_unreachable_page() {
+        let fetcher = StubFetcher::default();
+        let entry = fetch_entry(URL, &fetcher, &ogp_config(), now());
+        assert!(entry.is_failure());
+        assert_eq!(entry.fetched_at, Some(now()));
+        assert_eq!(requested(&fetcher), [URL]);
+    }
+
+    #[test]
+    fn saves_thumbnail() {
+        let dir = temp_dir("thumbnails");
+        let fetcher =
+            StubFetcher::default().with_image("https://example.com/cached.png", png(400, 200));
+
+        let name = save_thumbnail("https://example.com/cached.png", &fetcher, &dir, 100).unwrap();
+        let saved = image::open(dir.join(&name)).unwrap();
+        assert_eq!((saved.width(), saved.height()), (200, 100));
+
+        let mut entry = success(None);
+        assert_eq!(
+            CardImage::Thumbnail.missing(entry.info().unwrap(), &dir),
+            Some("https://example.com/cached.png")
+        );
+        let FetchResult::Info(info) = &mut entry.result else {
+            unreachable!()
+        };
+        info.thumbnail = Some(name.clone());
+        assert_eq!(
+            CardImage::Thumbnail.missing(entry.info().unwrap(), &dir),
+            None
+        );
+
+        let cache = Mutex::new(OgpEntries::from([(URL.to_string(), entry.clone())]));
+        let card = link_card(
+            URL,
+            &cache,
+            &OgpConfig::default(),
+            &dir,
+            &handlebars(NO_TEMPLATES),
+        )
+        .unwrap();
+        assert!(card.contains(&format!(r#"src="/ogp-thumbnails/{name}""#)));
+
+        // hotlinked until it is downloaded again
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(CardImage::Thumbnail
+            .missing(entry.info().unwrap(), &dir)
+            .is_some());
+        let card = link_card(
+            URL,
+            &cache,
+            &OgpConfig::default(),
+            &dir,
+            &handlebars(NO_TEMPLATES),
+        )
+        .unwrap();
+        assert!(card.contains(r#"src="https://example.com/cached.png""#));
+    }
+
+    #[test]
+    fn guesses_favicon_only_if_downloaded() {
+        let fetcher = StubFetcher::default().with_page(URL, PAGE);
+        let entry = fetch_entry(URL, &fetcher, &OgpConfig::default(), now());
+        let info = entry.info().unwrap();
+        assert_eq!(
+            info.icon_url.as_deref(),
+            Some("https://example.com/favicon.ico")
+        );
+        assert_eq!((&info.thumbnail, &info.icon), (&None, &None));
+        // images are downloaded later by `save_images`
+        assert_eq!(requested(&fetcher), [URL]);
+
+        let entry = fetch_entry(URL, &fetcher, &ogp_config(), now());
+        assert_eq!(entry.info().unwrap().icon_url, None);
+    }
+
+    #[test]
+    fn renders_card_with_template_for_type() {
+        let dir = temp_dir("templates");
+        std::fs::write(
+            dir.join("og_card_video.hbs"),
+            "<video-card>{{ title }} {{ properties.site_name }} {{ videos.0.url }}</video-card>",
+        )
+        .unwrap();
+        let handlebars = handlebars(dir.to_str().unwrap());
+        let info = OgpInfo {
+            og_type: "video.movie".to_string(),
+            title: "Movie".to_string(),
+            properties: [("site_name".to_string(), "Site".to_string())].into(),
+            videos: vec![OgpObject {
+                url: "https://example.com/movie.mp4".to_string(),
+                properties: Default::default(),
+            }],
+            ..OgpInfo::default()
+        };
+
+        assert_eq!(
+            render_card(&handlebars, URL, &info, Path::new(NO_THUMBNAILS)).unwrap(),
+            "<video-card>Movie Site https://example.com/movie.mp4</video-card>"
+        );
+        // others use the default one
+        let info = OgpInfo {
+            og_type: "article".to_string(),
+            ..info
+        };
+        let card = render_card(&handlebars, URL, &info, Path::new(NO_THUMBNAILS)).unwrap();
+        assert!(card.contains(r#"class="og-card og-type-article""#));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn renders_card_without_optional_fields() {
+        let info = OgpInfo {
+            og_type: "website".to_string(),
+            ..OgpInfo::default()
+        };
+        let card = render_card(
+            &handlebars(NO_TEMPLATES),
+            URL,
+            &info,
+            Path::new(NO_THUMBNAILS),
+        )
+        .unwrap();
+        assert!(card.contains(&format!(r#"<span class="og-title">{URL}</span>"#)));
+        assert!(!card.contains("<img"));
+    }
+}
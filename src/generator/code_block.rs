@@ -0,0 +1,270 @@
+use std::{ops::RangeInclusive, path::Path};
+
+use anyhow::{bail, Context as _};
+use log::warn;
+use maud::{html, PreEscaped};
+
+use crate::context::Context;
+
+use super::utils::split_attributes;
+
+/// Attributes given in the info string of a fenced code block,
+/// e.g. ```` ```rust title="main.rs" linenos hl_lines="3-5" ````.
+/// `linenos` and `hl_lines` count lines of an excerpt by `lines` as in the included file.
+#[derive(Debug, Default)]
+pub(super) struct CodeBlockInfo {
+    pub lang: String,
+    pub title: Option<String>,
+    pub linenos: bool,
+    pub hl_lines: Vec<RangeInclusive<usize>>,
+    /// file to show instead of the content, relative to the article (or to `article_dir` if it starts with `/`)
+    pub include: Option<String>,
+    /// lines of `include` to show
+    pub lines: Option<RangeInclusive<usize>>,
+}
+
+impl CodeBlockInfo {
+    pub fn parse(info: &str) -> anyhow::Result<Self> {
+        let mut result = Self::default();
+        for (i, (key, value)) in split_attributes(info)?.into_iter().enumerate() {
+            match (key.as_str(), value) {
+                (lang, None) if i == 0 => result.lang = lang.to_string(),
+                ("title", Some(v)) => result.title = Some(v),
+                ("linenos", None) => result.linenos = true,
+                ("hl_lines", Some(v)) => {
+                    result.hl_lines = v
+                        .split(|c: char| c == ',' || c.is_whitespace())
+                        .filter(|s| !s.is_empty())
+                        .map(parse_line_range)
+                        .collect::<anyhow::Result<_>>()?;
+                }
+                ("include", Some(v)) => result.include = Some(v),
+                ("lines", Some(v)) => result.lines = Some(parse_line_range(&v)?),
+                (key, _) => warn!("Unknown code block attribute: {key}"),
+            }
+        }
+        if result.lines.is_some() && result.include.is_none() {
+            bail!("`lines` is only available with `include`");
+        }
+
+        Ok(result)
+    }
+}
+
+/// `3`, `3-5`, `3-` or `-5` (1-based, inclusive)
+fn parse_line_range(s: &str) -> anyhow::Result<RangeInclusive<usize>> {
+    let parse = |n: &str, default: usize| -> anyhow::Result<usize> {
+        if n.is_empty() {
+            Ok(default)
+        } else {
+            n.trim()
+                .parse()
+                .with_context(|| format!("Invalid line range: {s}"))
+        }
+    };
+    let range = match s.split_once('-') {
+        Some((start, end)) => parse(start, 1)?..=parse(end, usize::MAX)?,
+        None => parse(s, 1)?..=parse(s, 1)?,
+    };
+    if *range.start() == 0 || range.is_empty() {
+        bail!("Invalid line range: {s}");
+    }
+
+    Ok(range)
+}
+
+/// Reads the lines of the file given by `include`.
+pub(super) fn read_include(article_relpath: &Path, info: &CodeBlockInfo) -> anyhow::Result<String> {
+    let ctx = Context::instance();
+    let Some(ref include) = info.include else {
+        return Ok(String::new());
+    };
+
+    let include_abspath = match include.strip_prefix('/') {
+        Some(p) => ctx.article_dir.join(p),
+        None => ctx
+            .article_dir
+            .join(article_relpath.parent().unwrap_or(Path::new("")))
+            .join(include),
+    };
+    let include_abspath = include_abspath
+        .canonicalize()
+        .with_context(|| format!("while resolving include {include:?}"))?;
+    if !include_abspath.starts_with(ctx.article_dir.canonicalize()?) {
+        bail!("Included file must be under article_dir: {include:?}");
+    }
+    let content = std::fs::read_to_string(&include_abspath)
+        .with_context(|| format!("while opening {:?}", include_abspath))?;
+
+    let Some(ref range) = info.lines else {
+        return Ok(content);
+    };
+    let lines: Vec<_> = content.lines().collect();
+    if *range.start() > lines.len() {
+        bail!(
+            "Line {} is out of {include:?} ({} lines)",
+            range.start(),
+            lines.len()
+        );
+    }
+    let end = (*range.end()).min(lines.len());
+    let mut snippet = lines[range.start() - 1..end].join("\n");
+    snippet.push('\n');
+
+    Ok(snippet)
+}
+
+pub(super) fn render_code_block(info: &CodeBlockInfo, code: &str) -> String {
+    let ctx = Context::instance();
+    let lang = &info.lang;
+    let highlighted = if lang.is_empty() {
+        None
+    } else {
+        ctx.highlighter
+            .highlight_lines(lang, code)
+            .unwrap_or_else(|e| {
+                warn!("Failed to highlight code block ({lang}): {e}");
+                None
+            })
+    };
+    let pre_class = highlighted.is_some().then_some("hl-code");
+    let (lines, pre_style) = match highlighted {
+        Some(lines) => (lines, ctx.highlighter.pre_style()),
+        // unknown language: plain code block, same as pulldown-cmark
+        None => (
+            code.lines().map(|l| html! { (l) }.into_string()).collect(),
+            None,
+        ),
+    };
+
+    let lang_class = (!lang.is_empty()).then(|| format!("language-{lang}"));
+    let per_line = info.linenos || !info.hl_lines.is_empty();
+    let first_lineno = info.lines.as_ref().map_or(1, |r| *r.start());
+    let pre = html! {
+        pre class=[pre_class] style=[pre_style] {
+            code class=[lang_class] {
+                @for (i, line) in lines.iter().enumerate() {
+                    @if per_line {
+                        @let lineno = first_lineno + i;
+                        span.code-line.highlighted[info.hl_lines.iter().any(|r| r.contains(&lineno))] {
+                            @if info.linenos {
+                                span.lineno { (lineno) }
+                            }
+                            (PreEscaped(line))
+                        }
+                    } @else {
+                        (PreEscaped(line))
+                    }
+                    "\n"
+                }
+            }
+        }
+    };
+
+    match info.title {
+        Some(ref title) => html! {
+            figure.code-block {
+                figcaption.code-title { (title) }
+                (pre)
+            }
+        },
+        None => pre,
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::init_context;
+
+    #[test]
+    fn numbers_lines_of_excerpt_as_in_file() {
+        init_context();
+        let info =
+            CodeBlockInfo::parse(r#"text include="config.toml" lines="3-4" linenos hl_lines="3""#)
+                .unwrap();
+        let html = render_code_block(&info, "third\nfourth\n");
+        assert!(html.contains(
+            r#"<span class="code-line highlighted"><span class="lineno">3</span>third</span>"#
+        ));
+        assert!(
+            html.contains(r#"<span class="code-line"><span class="lineno">4</span>fourth</span>"#)
+        );
+    }
+
+    #[test]
+    fn parses_info_string() {
+        let info = CodeBlockInfo::parse(
+            r#"rust title="src/main.rs" linenos hl_lines="1, 3-5 8-" unknown"#,
+        )
+        .unwrap();
+        assert_eq!(info.lang, "rust");
+        assert_eq!(info.title.as_deref(), Some("src/main.rs"));
+        assert!(info.linenos);
+        assert_eq!(info.hl_lines, [1..=1, 3..=5, 8..=usize::MAX]);
+        assert_eq!(info.include, None);
+
+        let info = CodeBlockInfo::parse(r#"toml include=/a.toml lines=-2"#).unwrap();
+        assert_eq!(info.include.as_deref(), Some("/a.toml"));
+        assert_eq!(info.lines, Some(1..=2));
+
+        for invalid in [
+            r#"rust lines="1-2""#,
+            r#"rust hl_lines="0""#,
+            r#"rust hl_lines="5-3""#,
+            r#"rust hl_lines="a""#,
+            r#"rust title="unterminated"#,
+        ] {
+            assert!(CodeBlockInfo::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn reads_included_lines() {
+        init_context();
+        let dir = Context::instance().article_dir.join("include");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "1\n2\n3\n4\n").unwrap();
+        let read =
+            |info: &str| read_include(Path::new("include/article"), &CodeBlockInfo::parse(info)?);
+
+        assert_eq!(read(r#"text include="a.txt""#).unwrap(), "1\n2\n3\n4\n");
+        assert_eq!(
+            read(r#"text include="a.txt" lines="2-3""#).unwrap(),
+            "2\n3\n"
+        );
+        assert_eq!(
+            read(r#"text include="/include/a.txt" lines="3-""#).unwrap(),
+            "3\n4\n"
+        );
+        assert!(read(r#"text include="a.txt" lines="5-""#).is_err());
+        assert!(read(r#"text include="missing.txt""#).is_err());
+    }
+
+    #[test]
+    fn rejects_includes_outside_article_dir() {
+        init_context();
+        let article_dir = &Context::instance().article_dir;
+        std::fs::create_dir_all(article_dir).unwrap();
+        // next to `article_dir`
+        let secret = article_dir.with_file_name("secret.txt");
+        std::fs::write(&secret, "secret\n").unwrap();
+
+        let link = article_dir.join("link.txt");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(&secret, &link).unwrap();
+
+        for include in ["../secret.txt", "/../secret.txt", "link.txt"] {
+            let info = CodeBlockInfo {
+                include: Some(include.to_string()),
+                ..CodeBlockInfo::default()
+            };
+            let error = read_include(Path::new("article"), &info).unwrap_err();
+            assert!(
+                error.to_string().contains("must be under article_dir"),
+                "{include}: {error}"
+            );
+        }
+    }
+}
@@ -1,14 +1,28 @@
 use serde::Serialize;
 use std::{fs::Metadata as FileMetadata, path::PathBuf, rc::Rc};
 
+use crate::config::LineBreak;
+
 #[derive(Serialize, Debug, Clone)]
 pub(super) struct ArticleMetadata {
     pub title: String,
     pub tags: Vec<String>,
     pub date: Option<chrono::NaiveDate>,
     pub relpath: PathBuf,
+    /// `relpath` with the extension of the source file
+    #[serde(skip_serializing)]
+    pub source_relpath: PathBuf,
     pub is_page: bool,
     pub body: String,
+    /// line number in the source file where `body` starts
+    #[serde(skip_serializing)]
+    pub body_line: usize,
+    /// whether table of contents is shown (`toc` in the header).
+    /// `None` shows it if there are `heading.toc_min_headings` or more.
+    pub toc: Option<bool>,
+    /// overrides `markdown.line_break` of the config (`line_break` in the header)
+    #[serde(skip_serializing)]
+    pub line_break: Option<LineBreak>,
 
     #[serde(skip_serializing)]
     pub file_meta: FileMetadata,
@@ -21,8 +35,12 @@ impl ArticleMetadata {
             tags: Vec::new(),
             date: None,
             relpath: PathBuf::new(),
+            source_relpath: PathBuf::new(),
             is_page: false,
             body: String::new(),
+            body_line: 1,
+            toc: None,
+            line_break: None,
             file_meta,
         }
     }
@@ -31,16 +49,31 @@ impl ArticleMetadata {
 #[derive(Serialize, Debug)]
 pub(super) struct ArticlePageData<'a> {
     pub blog_name: &'static str,
+    /// whether `highlight.css` exists
+    pub highlight_stylesheet: bool,
     pub body: String,
     pub meta: &'a ArticleMetadata,
     pub prev_meta: Option<&'a ArticleMetadata>,
     pub next_meta: Option<&'a ArticleMetadata>,
+    pub toc: Vec<TocEntry>,
+    /// articles linking to this one
+    pub backlinks: Vec<&'a ArticleMetadata>,
     pub use_giscus: bool,
 }
 
+#[derive(Serialize, Debug)]
+pub(super) struct TocEntry {
+    pub level: usize,
+    pub id: String,
+    pub title: String,
+    pub children: Vec<TocEntry>,
+}
+
 #[derive(Serialize, Debug)]
 pub(super) struct ListPageData {
     pub blog_name: &'static str,
+    /// whether `highlight.css` exists
+    pub highlight_stylesheet: bool,
     pub title: String,
     pub relpath: PathBuf,
     pub is_page: bool,
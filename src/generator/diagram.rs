@@ -0,0 +1,143 @@
+use std::{
+    fs::create_dir_all,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::LazyLock,
+};
+
+use anyhow::{anyhow, Context as _};
+use layout::{
+    backends::svg::SVGWriter,
+    gv::{DotParser, GraphBuilder},
+};
+use log::debug;
+use maud::{html, PreEscaped};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+use crate::context::Context;
+
+/// bumped when output of renderers changes, to invalidate cached diagrams
+const RENDERER_VERSION: &str = "layout-rs-0.1-r2";
+
+/// `id="..."` and references to it in SVG
+static ID_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r##"\bid="|url\(#|href="#"##).unwrap());
+
+/// Whether a fenced code block of `lang` is rendered as a diagram.
+pub(super) fn is_diagram(lang: &str) -> bool {
+    matches!(lang, "dot")
+}
+
+/// Renders `source` into `<figure>` with inline SVG.
+pub(super) fn render_diagram(
+    lang: &str,
+    source: &str,
+    title: Option<&str>,
+) -> anyhow::Result<String> {
+    let svg = render_svg(lang, source)?;
+    Ok(html! {
+        figure class={ "diagram diagram-" (lang) } {
+            (PreEscaped(svg))
+            @if let Some(title) = title {
+                figcaption { (title) }
+            }
+        }
+    }
+    .into_string())
+}
+
+/// Results are cached by the hash of the source.
+/// Ids in the SVG are prefixed with the hash too, so that diagrams in a page do not refer to markers of each other.
+fn render_svg(lang: &str, source: &str) -> anyhow::Result<String> {
+    let ctx = Context::instance();
+    let hash = Sha256::digest(format!("{RENDERER_VERSION}\0{lang}\0{source}"));
+    let cache_path = ctx.diagram_cache_dir.join(format!("{hash:x}.svg"));
+    if let Ok(svg) = std::fs::read_to_string(&cache_path) {
+        debug!("Using cached diagram {cache_path:?}");
+        return Ok(svg);
+    }
+
+    let svg = match lang {
+        "dot" => render_dot(source)?,
+        _ => unreachable!("{lang} is not a diagram"),
+    };
+    let svg = prefix_ids(&svg, &format!("d{:.12}-", format!("{hash:x}")));
+
+    create_dir_all(&ctx.diagram_cache_dir)
+        .with_context(|| format!("while making {:?}", ctx.diagram_cache_dir))?;
+    std::fs::write(&cache_path, &svg).with_context(|| format!("while writing {cache_path:?}"))?;
+
+    Ok(svg)
+}
+
+fn render_dot(source: &str) -> anyhow::Result<String> {
+    let graph = DotParser::new(source)
+        .process()
+        .map_err(|e| anyhow!("Syntax error in dot: {e}"))?;
+
+    // layout-rs panics on some inputs instead of returning errors
+    let svg = catch_unwind(AssertUnwindSafe(|| {
+        let mut builder = GraphBuilder::new();
+        builder.visit_graph(&graph);
+        let mut visual_graph = builder.get();
+        let mut writer = SVGWriter::new();
+        visual_graph.do_it(false, false, false, &mut writer);
+        writer.finalize()
+    }))
+    .map_err(|_| anyhow!("Failed to lay out dot graph"))?;
+
+    // XML declaration is not allowed in HTML
+    Ok(match svg.split_once("?>") {
+        Some((_, rest)) if svg.starts_with("<?xml") => rest.to_string(),
+        _ => svg,
+    })
+}
+
+/// Prepends `prefix` to ids in `svg`, and to references to them.
+fn prefix_ids(svg: &str, prefix: &str) -> String {
+    ID_PATTERN
+        .replace_all(svg, |caps: &regex::Captures| {
+            format!("{}{prefix}", &caps[0])
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::init_context;
+    use std::collections::HashSet;
+
+    static ID: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"\bid="([^"]+)""#).unwrap());
+    static REFERENCE: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r##"url\(#([^)]+)\)|href="#([^"]+)""##).unwrap());
+
+    fn ids(svg: &str) -> HashSet<&str> {
+        ID.captures_iter(svg)
+            .map(|c| c.get(1).unwrap().as_str())
+            .collect()
+    }
+
+    /// `d{hash}-` of the first id
+    fn prefix(svg: &str) -> &str {
+        let id = ID.captures(svg).unwrap().get(1).unwrap().as_str();
+        &id[..id.find('-').unwrap() + 1]
+    }
+
+    #[test]
+    fn prefixes_ids_per_diagram() {
+        init_context();
+        let a = render_svg("dot", "digraph { a -> b [label=x] }").unwrap();
+        let b = render_svg("dot", "digraph { c -> d [label=y] }").unwrap();
+
+        let (ids_a, ids_b) = (ids(&a), ids(&b));
+        assert!(ids_a.contains(&*format!("{}endarrow", prefix(&a))));
+        assert!(ids_a.is_disjoint(&ids_b));
+        for svg in [&a, &b] {
+            for caps in REFERENCE.captures_iter(svg) {
+                let id = caps.get(1).or(caps.get(2)).unwrap().as_str();
+                assert!(ids(svg).contains(id), "{id} is not in the diagram");
+            }
+        }
+    }
+}
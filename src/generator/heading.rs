@@ -0,0 +1,205 @@
+use std::collections::HashSet;
+
+use maud::html;
+use pulldown_cmark::{html, CowStr, Event, HeadingLevel, Options, Parser, Tag, TagEnd};
+
+use crate::{config::HeadingOffset, context::Context};
+
+use super::data::TocEntry;
+
+/// `(level, id, title)` of headings in an article
+pub(super) type Headings = Vec<(usize, String, String)>;
+
+/// Levels to demote headings in `body` by, following the config.
+pub(super) fn heading_offset(body: &str, options: Options) -> usize {
+    let config = &Context::instance().config.heading;
+    match config.offset {
+        HeadingOffset::Fixed(offset) => offset,
+        HeadingOffset::Auto => Parser::new_ext(body, options)
+            .filter_map(|e| match e {
+                Event::Start(Tag::Heading { level, .. }) => Some(level as usize),
+                _ => None,
+            })
+            .min()
+            .map_or(0, |top| config.top_level.saturating_sub(top)),
+    }
+}
+
+pub(super) fn demote(level: HeadingLevel, offset: usize) -> HeadingLevel {
+    HeadingLevel::try_from((level as usize + offset).min(6)).unwrap()
+}
+
+/// A heading whose content is being read by the event iterator.
+#[derive(Debug)]
+pub(super) struct PendingHeading<'a> {
+    pub level: HeadingLevel,
+    pub id: Option<CowStr<'a>>,
+    pub classes: Vec<CowStr<'a>>,
+    pub attrs: Vec<(CowStr<'a>, Option<CowStr<'a>>)>,
+    pub inner: Vec<Event<'a>>,
+    /// plain text of the content, used for slug and table of contents
+    pub text: String,
+}
+
+/// Assigns ids to headings of an article without collision.
+#[derive(Debug, Default)]
+pub(super) struct Slugger {
+    used: HashSet<String>,
+}
+
+impl Slugger {
+    /// `explicit` is an id given by `{#id}` syntax, which is used as it is if possible.
+    pub fn slug(&mut self, text: &str, explicit: Option<&str>) -> String {
+        let base = match explicit {
+            Some(id) => id.to_string(),
+            None => slugify(text),
+        };
+        let mut slug = base.clone();
+        let mut i = 1;
+        while !self.used.insert(slug.clone()) {
+            slug = format!("{base}-{i}");
+            i += 1;
+        }
+
+        slug
+    }
+}
+
+/// Lowercases `text` and joins runs of alphanumerics (including kana and kanji) with `-`.
+fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() || c == '_' {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+
+    if slug.is_empty() {
+        "section".to_string()
+    } else {
+        slug.to_string()
+    }
+}
+
+pub(super) fn render_heading(heading: PendingHeading, id: &str, permalink: bool) -> String {
+    let level = heading.level;
+    let mut events = vec![Event::Start(Tag::Heading {
+        level,
+        id: Some(id.to_string().into()),
+        classes: heading.classes,
+        attrs: heading.attrs,
+    })];
+    events.extend(heading.inner);
+    if permalink {
+        events.push(Event::Html(
+            html! { a.heading-anchor href={ "#" (id) } aria-label="permalink" { "#" } }
+                .into_string()
+                .into(),
+        ));
+    }
+    events.push(Event::End(TagEnd::Heading(level)));
+
+    let mut rendered = String::new();
+    html::push_html(&mut rendered, events.into_iter());
+    rendered
+}
+
+/// Nests flat list of headings by their levels.
+pub(super) fn build_toc(headings: Headings) -> Vec<TocEntry> {
+    fn push(entries: &mut Vec<TocEntry>, entry: TocEntry) {
+        match entries.last_mut() {
+            Some(last) if last.level < entry.level => push(&mut last.children, entry),
+            _ => entries.push(entry),
+        }
+    }
+
+    let mut toc = vec![];
+    for (level, id, title) in headings {
+        push(
+            &mut toc,
+            TocEntry {
+                level,
+                id,
+                title,
+                children: vec![],
+            },
+        );
+    }
+
+    toc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generator::markdown_options, test_utils::init_context};
+
+    #[test]
+    fn slugifies_heading_text() {
+        assert_eq!(slugify("Hello, World!"), "hello-world");
+        assert_eq!(
+            slugify("  snake_case  and  spaces "),
+            "snake_case-and-spaces"
+        );
+        assert_eq!(slugify("日本語の見出し (2)"), "日本語の見出し-2");
+        assert_eq!(slugify("!?"), "section");
+    }
+
+    #[test]
+    fn numbers_colliding_slugs() {
+        let mut slugger = Slugger::default();
+        assert_eq!(slugger.slug("Intro", None), "intro");
+        assert_eq!(slugger.slug("intro", None), "intro-1");
+        assert_eq!(slugger.slug("Intro!", None), "intro-2");
+        assert_eq!(slugger.slug("Other", Some("custom")), "custom");
+        assert_eq!(slugger.slug("Other", Some("custom")), "custom-1");
+        // an explicit id which looks like a numbered one
+        assert_eq!(slugger.slug("", Some("intro-3")), "intro-3");
+        assert_eq!(slugger.slug("Intro", None), "intro-4");
+    }
+
+    #[test]
+    fn nests_toc_entries_by_level() {
+        let heading = |level, id: &str| (level, id.to_string(), id.to_uppercase());
+        let toc = build_toc(vec![
+            // deeper than the next one, but there is nothing to nest it into
+            heading(4, "pre"),
+            heading(3, "a"),
+            heading(4, "a1"),
+            heading(5, "a1x"),
+            heading(4, "a2"),
+            heading(3, "b"),
+            heading(4, "b1"),
+        ]);
+
+        let ids = |entries: &[TocEntry]| entries.iter().map(|e| e.id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids(&toc), ["pre", "a", "b"]);
+        assert_eq!(ids(&toc[1].children), ["a1", "a2"]);
+        assert_eq!(ids(&toc[1].children[0].children), ["a1x"]);
+        assert_eq!(ids(&toc[2].children), ["b1"]);
+        assert_eq!(toc[1].title, "A");
+    }
+
+    #[test]
+    fn demotes_shallowest_heading_to_top_level() {
+        init_context();
+        let offset = |body| heading_offset(body, markdown_options());
+        // `heading.top_level` is 3 by default
+        assert_eq!(offset("# Title\n\n## Section\n"), 2);
+        assert_eq!(offset("text\n\n## Section\n\n### Sub\n"), 1);
+        assert_eq!(offset("#### Deep\n"), 0);
+        assert_eq!(offset("no headings\n"), 0);
+        // not a heading in code
+        assert_eq!(offset("```\n# comment\n```\n\n### Section\n"), 0);
+    }
+
+    #[test]
+    fn demotes_up_to_h6() {
+        assert_eq!(demote(HeadingLevel::H1, 2), HeadingLevel::H3);
+        assert_eq!(demote(HeadingLevel::H2, 0), HeadingLevel::H2);
+        assert_eq!(demote(HeadingLevel::H5, 3), HeadingLevel::H6);
+    }
+}
@@ -0,0 +1,491 @@
+use std::{
+    fs::create_dir_all,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context as _};
+use image::{
+    codecs::avif::AvifEncoder, imageops::FilterType, metadata::Orientation, DynamicImage,
+    ImageDecoder, ImageReader,
+};
+use log::debug;
+use maud::{html, PreEscaped};
+use pulldown_cmark::{CowStr, Event, Tag, TagEnd};
+use sha2::{Digest, Sha256};
+
+use crate::{config::ImageFormat, context::Context};
+
+use super::{
+    data::ArticleMetadata,
+    link::{asset_url, ArticleIndex},
+};
+
+/// bumped when output of encoders changes, to invalidate cached images
+const ENCODER_VERSION: &str = "image-0.25,webp-0.3";
+
+/// AVIF encoder speed (1-10): slower ones compress only slightly better, but take much longer
+const AVIF_SPEED: u8 = 8;
+
+/// An image whose alt text is being read by the event iterator.
+#[derive(Debug)]
+pub(super) struct PendingImage<'a> {
+    pub dest_url: CowStr<'a>,
+    pub title: CowStr<'a>,
+    pub alt: String,
+    /// number of images open in the alt text, such as `b` of `![a ![b](i.png)](o.png)`
+    depth: usize,
+}
+
+impl<'a> PendingImage<'a> {
+    pub fn new(dest_url: CowStr<'a>, title: CowStr<'a>) -> Self {
+        Self {
+            dest_url,
+            title,
+            alt: String::new(),
+            depth: 0,
+        }
+    }
+
+    /// Reads an event in the alt text, and returns whether it ends the image.
+    /// Formatting and nested images are flattened into text.
+    pub fn read(&mut self, event: &Event) -> bool {
+        match event {
+            Event::Start(Tag::Image { .. }) => self.depth += 1,
+            Event::End(TagEnd::Image) if self.depth == 0 => return true,
+            Event::End(TagEnd::Image) => self.depth -= 1,
+            Event::Text(text) | Event::Code(text) => self.alt.push_str(text),
+            _ => {}
+        }
+        false
+    }
+}
+
+/// Intrinsic size of an image and its resized variants for each format.
+#[derive(Debug)]
+struct ProcessedImage {
+    width: u32,
+    height: u32,
+    sources: Vec<(ImageFormat, Vec<Variant>)>,
+}
+
+/// A resized image written into `out_dir`.
+#[derive(Debug)]
+struct Variant {
+    url: String,
+    width: u32,
+}
+
+impl ImageFormat {
+    pub(super) fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Avif => "avif",
+            ImageFormat::Webp => "webp",
+        }
+    }
+
+    fn mime_type(self) -> &'static str {
+        match self {
+            ImageFormat::Avif => "image/avif",
+            ImageFormat::Webp => "image/webp",
+        }
+    }
+
+    pub(super) fn encode(self, image: &DynamicImage, quality: u8) -> anyhow::Result<Vec<u8>> {
+        match self {
+            ImageFormat::Avif => {
+                let mut encoded = vec![];
+                image.write_with_encoder(AvifEncoder::new_with_speed_quality(
+                    &mut encoded,
+                    AVIF_SPEED,
+                    quality,
+                ))?;
+                Ok(encoded)
+            }
+            ImageFormat::Webp => {
+                // libwebp accepts only 8-bit RGB(A)
+                let image = if image.color().has_alpha() {
+                    DynamicImage::from(image.to_rgba8())
+                } else {
+                    DynamicImage::from(image.to_rgb8())
+                };
+                let encoder = webp::Encoder::from_image(&image).map_err(|e| anyhow!("{e}"))?;
+                Ok(encoder.encode(quality as f32).to_vec())
+            }
+        }
+    }
+}
+
+/// Wraps images alone in paragraphs into `<figure>`s, so that their titles are shown as captions.
+/// Two or more of them in a paragraph become a gallery if `images.gallery` is set.
+/// This needs the whole paragraph, so it cannot be done in `gen_parser_event_iterator`.
+/// Added events have the offset of the paragraph.
+pub(super) fn wrap_images<'a>(
+    metadata: &ArticleMetadata,
+    events: Vec<(Event<'a>, usize)>,
+) -> Vec<(Event<'a>, usize)> {
+    let gallery = Context::instance().config.images.gallery;
+    let mut result = Vec::with_capacity(events.len());
+    let mut rest = events.into_iter();
+    while let Some((event, offset)) = rest.next() {
+        if event != Event::Start(Tag::Paragraph) {
+            result.push((event, offset));
+            continue;
+        }
+        let mut paragraph = vec![];
+        let mut end_offset = offset;
+        for (e, o) in rest.by_ref() {
+            if e == Event::End(TagEnd::Paragraph) {
+                end_offset = o;
+                break;
+            }
+            paragraph.push((e, o));
+        }
+
+        let is_gallery = |images: &[(Option<String>, usize)]| gallery && images.len() >= 2;
+        let Some(images) = split_images(&paragraph)
+            .filter(|images| is_gallery(images) || images.iter().any(|(title, _)| title.is_some()))
+        else {
+            result.push((Event::Start(Tag::Paragraph), offset));
+            result.extend(paragraph);
+            result.push((Event::End(TagEnd::Paragraph), end_offset));
+            continue;
+        };
+
+        let is_gallery = is_gallery(&images);
+        if is_gallery {
+            result.push((Event::Html(r#"<div class="gallery">"#.into()), offset));
+        }
+        let mut paragraph = paragraph.into_iter();
+        for (title, len) in images {
+            let mut image: Vec<_> = paragraph
+                .by_ref()
+                .skip_while(|(e, _)| !matches!(e, Event::Start(Tag::Image { .. })))
+                .take(len)
+                .collect();
+            // the title is shown as the caption instead of a tooltip
+            if title.is_some() {
+                if let Event::Start(Tag::Image { ref mut title, .. }) = image[0].0 {
+                    *title = "".into();
+                }
+            }
+            let caption = title
+                .map(|title| html! { figcaption { (title) } }.into_string())
+                .unwrap_or_default();
+            if is_gallery {
+                // linked to the original for lightboxes
+                let Event::Start(Tag::Image { ref dest_url, .. }) = image[0].0 else {
+                    unreachable!();
+                };
+                let href = match ArticleIndex::instance().resolve_link(&metadata.relpath, dest_url)
+                {
+                    Ok(Some(url)) => url,
+                    _ => dest_url.to_string(),
+                };
+                result.push((
+                    Event::Html(
+                        format!(
+                            r#"<figure class="gallery-item"><a href="{}">"#,
+                            html! { (href) }.into_string()
+                        )
+                        .into(),
+                    ),
+                    offset,
+                ));
+                result.extend(image);
+                result.push((
+                    Event::Html(format!("</a>{caption}</figure>").into()),
+                    offset,
+                ));
+            } else {
+                result.push((Event::Html(r#"<figure class="image">"#.into()), offset));
+                result.extend(image);
+                result.push((Event::Html(format!("{caption}</figure>").into()), offset));
+            }
+        }
+        if is_gallery {
+            result.push((Event::Html("</div>".into()), offset));
+        }
+    }
+
+    result
+}
+
+/// Titles and numbers of events of images, if `paragraph` consists only of images and spaces between them.
+fn split_images(paragraph: &[(Event, usize)]) -> Option<Vec<(Option<String>, usize)>> {
+    let mut images = vec![];
+    let mut events = paragraph.iter().map(|(e, _)| e);
+    while let Some(event) = events.next() {
+        match event {
+            Event::Start(Tag::Image { title, .. }) => {
+                let mut len = 1;
+                let mut depth = 1;
+                for e in events.by_ref() {
+                    len += 1;
+                    match e {
+                        Event::Start(Tag::Image { .. }) => depth += 1,
+                        Event::End(TagEnd::Image) => depth -= 1,
+                        _ => {}
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                images.push((Some(title.to_string()).filter(|t| !t.is_empty()), len));
+            }
+            Event::SoftBreak | Event::HardBreak => {}
+            Event::Text(text) if text.trim().is_empty() => {}
+            _ => return None,
+        }
+    }
+
+    (!images.is_empty()).then_some(images)
+}
+
+/// Whether resized variants are made from the image. Others (such as SVG and animated GIF) are used as they are.
+fn is_resizable(relpath: &Path) -> bool {
+    relpath.extension().is_some_and(|ext| {
+        ["png", "jpg", "jpeg", "webp"]
+            .iter()
+            .any(|e| ext.eq_ignore_ascii_case(e))
+    })
+}
+
+/// Renders an image. Local ones are converted into `<picture>` with resized variants.
+pub(super) fn render_image(
+    metadata: &ArticleMetadata,
+    image: &PendingImage,
+) -> anyhow::Result<String> {
+    let ctx = Context::instance();
+    let title = Some(&*image.title).filter(|t| !t.is_empty());
+    let Some(relpath) = ArticleIndex::instance().resolve_asset(&metadata.relpath, &image.dest_url)
+    else {
+        return Ok(render_img(&image.dest_url, &image.alt, title, None));
+    };
+    let url = asset_url(&relpath);
+    if !ctx.config.images.enabled || !is_resizable(&relpath) {
+        return Ok(render_img(&url, &image.alt, title, None));
+    }
+
+    let processed =
+        process_image(&relpath, &url).with_context(|| format!("while processing {relpath:?}"))?;
+    let sizes = &ctx.config.images.sizes;
+    Ok(html! {
+        picture {
+            @for (format, variants) in processed.sources.iter() {
+                source type=(format.mime_type()) sizes=(sizes) srcset=(srcset(variants));
+            }
+            (PreEscaped(render_img(&url, &image.alt, title, Some((processed.width, processed.height)))))
+        }
+    }
+    .into_string())
+}
+
+fn render_img(src: &str, alt: &str, title: Option<&str>, size: Option<(u32, u32)>) -> String {
+    html! {
+        img src=(src) alt=(alt) title=[title]
+            width=[size.map(|s| s.0)] height=[size.map(|s| s.1)]
+            loading="lazy" decoding="async";
+    }
+    .into_string()
+}
+
+fn srcset(variants: &[Variant]) -> String {
+    variants
+        .iter()
+        .map(|v| format!("{} {}w", v.url, v.width))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Writes resized variants of the image at `relpath` next to its copy in `out_dir`.
+/// Encoded images are cached by the hash of the source, so decoding is skipped if all of them are cached.
+fn process_image(relpath: &Path, url: &str) -> anyhow::Result<ProcessedImage> {
+    let ctx = Context::instance();
+    let config = &ctx.config.images;
+    let source = std::fs::read(ctx.article_dir.join(relpath))?;
+    let source_hash = Sha256::digest(&source);
+
+    let mut decoder = ImageReader::new(Cursor::new(&source))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let (mut width, mut height) = decoder.dimensions();
+    if matches!(
+        orientation,
+        Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH
+    ) {
+        (width, height) = (height, width);
+    }
+
+    let mut widths: Vec<u32> = config
+        .widths
+        .iter()
+        .copied()
+        .filter(|&w| w < width)
+        .chain([width])
+        .collect();
+    widths.sort_unstable();
+    widths.dedup();
+
+    let mut decoder = Some(decoder);
+    let mut decoded: Option<DynamicImage> = None;
+    let file_name = relpath.file_name().unwrap().to_string_lossy();
+    let out_dir = ctx.out_dir.join(relpath.parent().unwrap());
+    let mut sources = vec![];
+    for &format in config.formats.iter() {
+        let mut variants = vec![];
+        for &w in widths.iter() {
+            let hash = Sha256::digest(format!(
+                "{ENCODER_VERSION}\0{source_hash:x}\0{w}\0{}\0{}",
+                format.extension(),
+                config.quality
+            ));
+            let cache_path = ctx
+                .image_cache_dir
+                .join(format!("{hash:x}.{}", format.extension()));
+            if cache_path.exists() {
+                debug!("Using cached image {cache_path:?}");
+            } else {
+                if decoded.is_none() {
+                    let mut image = DynamicImage::from_decoder(decoder.take().unwrap())?;
+                    image.apply_orientation(orientation);
+                    decoded = Some(image);
+                }
+                let image = decoded.as_ref().unwrap();
+                let h = (height as u64 * w as u64 / width as u64).max(1) as u32;
+                let encoded = format.encode(
+                    &image.resize_exact(w, h, FilterType::Lanczos3),
+                    config.quality,
+                )?;
+                create_dir_all(&ctx.image_cache_dir)
+                    .with_context(|| format!("while making {:?}", ctx.image_cache_dir))?;
+                std::fs::write(&cache_path, encoded)
+                    .with_context(|| format!("while writing {cache_path:?}"))?;
+            }
+
+            let variant_name = format!("{file_name}.{w}w.{}", format.extension());
+            let out_path: PathBuf = out_dir.join(&variant_name);
+            std::fs::copy(&cache_path, &out_path)
+                .with_context(|| format!("while copying {cache_path:?} to {out_path:?}"))?;
+            variants.push(Variant {
+                url: format!("{url}.{w}w.{}", format.extension()),
+                width: w,
+            });
+        }
+        sources.push((format, variants));
+    }
+
+    Ok(ProcessedImage {
+        width,
+        height,
+        sources,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{init_context, png};
+    use pulldown_cmark::Parser;
+
+    #[test]
+    fn reads_nested_images_as_alt_text() {
+        let mut events = Parser::new("![a *b* ![c `d`](i.png) e](o.png) f");
+        let Some(Event::Start(Tag::Image {
+            dest_url, title, ..
+        })) = events.find(|e| matches!(e, Event::Start(Tag::Image { .. })))
+        else {
+            panic!("no image");
+        };
+        let mut image = PendingImage::new(dest_url, title);
+
+        assert!(events.by_ref().any(|e| image.read(&e)));
+        assert_eq!(&*image.dest_url, "o.png");
+        assert_eq!(image.alt, "a b c d e");
+        assert_eq!(events.next(), Some(Event::Text(" f".into())));
+    }
+
+    #[test]
+    fn moves_title_of_lone_image_into_caption() {
+        init_context();
+        let metadata = ArticleMetadata::new(std::fs::metadata(".").unwrap());
+        let events = wrap_images(
+            &metadata,
+            Parser::new("![alt](a.png \"Caption\")\n")
+                .into_offset_iter()
+                .map(|(e, range)| (e, range.start))
+                .collect(),
+        );
+        let events: Vec<_> = events.into_iter().map(|(e, _)| e).collect();
+
+        assert_eq!(events[0], Event::Html(r#"<figure class="image">"#.into()));
+        let Event::Start(Tag::Image { ref title, .. }) = events[1] else {
+            panic!("no image");
+        };
+        assert_eq!(&**title, "");
+        assert_eq!(
+            events.last(),
+            Some(&Event::Html(
+                "<figcaption>Caption</figcaption></figure>".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn resizes_local_images_into_cached_variants() {
+        init_context();
+        let ctx = Context::instance();
+        let relpath = Path::new("resize/wide.png");
+        std::fs::create_dir_all(ctx.article_dir.join("resize")).unwrap();
+        std::fs::create_dir_all(ctx.out_dir.join("resize")).unwrap();
+        std::fs::write(ctx.article_dir.join(relpath), png(1000, 10)).unwrap();
+
+        let processed = process_image(relpath, "/resize/wide.png").unwrap();
+        assert_eq!((processed.width, processed.height), (1000, 10));
+        let [(ImageFormat::Webp, ref variants)] = processed.sources[..] else {
+            panic!("{:?}", processed.sources);
+        };
+        // narrower ones of the default widths, and the original
+        assert_eq!(
+            srcset(variants),
+            "/resize/wide.png.480w.webp 480w, /resize/wide.png.960w.webp 960w, \
+             /resize/wide.png.1000w.webp 1000w"
+        );
+        for width in [480, 960, 1000] {
+            let out_path = ctx.out_dir.join(format!("resize/wide.png.{width}w.webp"));
+            assert_eq!(image::open(out_path).unwrap().width(), width);
+        }
+
+        // the same source is not encoded again
+        let cached: Vec<_> = std::fs::read_dir(&ctx.image_cache_dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        for path in cached.iter() {
+            std::fs::write(path, "cached").unwrap();
+        }
+        std::fs::copy(
+            ctx.article_dir.join(relpath),
+            ctx.article_dir.join("resize/copy.png"),
+        )
+        .unwrap();
+        process_image(Path::new("resize/copy.png"), "/resize/copy.png").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(ctx.out_dir.join("resize/copy.png.480w.webp")).unwrap(),
+            "cached"
+        );
+    }
+
+    #[test]
+    fn resizes_only_raster_images() {
+        assert!(is_resizable(Path::new("a.PNG")));
+        assert!(is_resizable(Path::new("dir/a.jpeg")));
+        assert!(!is_resizable(Path::new("a.svg")));
+        assert!(!is_resizable(Path::new("a.gif")));
+        assert!(!is_resizable(Path::new("png")));
+    }
+}
@@ -0,0 +1,376 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Component, Path, PathBuf},
+    sync::{LazyLock, OnceLock},
+};
+
+use anyhow::{anyhow, bail};
+use maud::html;
+use pulldown_cmark::{Event, LinkType, Parser, Tag, TagEnd, TextMergeStream};
+use regex::Regex;
+
+use super::{data::ArticleMetadata, markdown_options};
+use crate::context::Context;
+
+/// `[[target]]` or `[[target|label]]`, where target is a path from `article_dir` or a title
+pub(super) static WIKI_LINK_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\[\[(?<target>[^\[\]|\n]+)(?:\|(?<label>[^\[\]\n]+))?\]\]").unwrap()
+});
+
+/// Articles, directories and tags in the output, to resolve internal links.
+#[derive(Debug, Default)]
+pub(super) struct ArticleIndex {
+    /// relpath (without extension) to title
+    articles: HashMap<PathBuf, String>,
+    directories: HashSet<PathBuf>,
+    tags: HashSet<String>,
+    /// non-article files copied into the output
+    assets: HashSet<PathBuf>,
+    /// title to relpaths
+    titles: HashMap<String, Vec<PathBuf>>,
+}
+
+enum Target {
+    Article(PathBuf),
+    Directory(PathBuf),
+    Tag(PathBuf),
+    Asset(PathBuf),
+}
+
+static ARTICLE_INDEX: OnceLock<ArticleIndex> = OnceLock::new();
+
+impl ArticleIndex {
+    pub fn init(
+        articles: impl IntoIterator<Item = (PathBuf, String)>,
+        directories: impl IntoIterator<Item = PathBuf>,
+        tags: impl IntoIterator<Item = String>,
+        assets: impl IntoIterator<Item = PathBuf>,
+    ) {
+        let articles: HashMap<_, _> = articles.into_iter().collect();
+        let mut titles: HashMap<String, Vec<PathBuf>> = HashMap::new();
+        for (relpath, title) in articles.iter() {
+            titles
+                .entry(title.clone())
+                .or_default()
+                .push(relpath.clone());
+        }
+        ARTICLE_INDEX
+            .set(Self {
+                articles,
+                directories: directories.into_iter().collect(),
+                tags: tags.into_iter().collect(),
+                assets: assets.into_iter().collect(),
+                titles,
+            })
+            .unwrap();
+    }
+
+    pub fn instance() -> &'static ArticleIndex {
+        ARTICLE_INDEX.get().unwrap()
+    }
+
+    /// Rewrites `dest` of a link or an image in the article at `from` into the output URL.
+    /// Links to articles, extensionless ones and existing assets are internal; `Ok(None)` is returned for others.
+    pub fn resolve_link(&self, from: &Path, dest: &str) -> anyhow::Result<Option<String>> {
+        let suffix = &dest[dest.find(['#', '?']).unwrap_or(dest.len())..];
+        Ok(self.resolve_target(from, dest)?.map(|target| {
+            let url = match target {
+                Target::Article(relpath) => article_url(&relpath),
+                Target::Directory(relpath) => directory_url(&relpath),
+                Target::Tag(relpath) => format!("/{}.html", url_path(&relpath)),
+                Target::Asset(relpath) => asset_url(&relpath),
+            };
+            format!("{url}{suffix}")
+        }))
+    }
+
+    /// Relpath of the asset linked from the article at `from`, if `dest` points to one.
+    pub fn resolve_asset(&self, from: &Path, dest: &str) -> Option<PathBuf> {
+        match self.resolve_target(from, dest) {
+            Ok(Some(Target::Asset(relpath))) => Some(relpath),
+            _ => None,
+        }
+    }
+
+    fn resolve_target(&self, from: &Path, dest: &str) -> anyhow::Result<Option<Target>> {
+        if dest.is_empty() || dest.starts_with('#') || dest.starts_with("//") || dest.contains(':')
+        {
+            return Ok(None);
+        }
+        let path = &dest[..dest.find(['#', '?']).unwrap_or(dest.len())];
+        let target = match path.strip_prefix('/') {
+            Some(p) => normalize(Path::new(p)),
+            None => normalize(&from.parent().unwrap_or(Path::new("")).join(path)),
+        }
+        .ok_or_else(|| anyhow!("Link {dest:?} points outside of article_dir"))?;
+
+        if Context::instance().config.articles.is_article(&target) {
+            let target = target.with_extension("");
+            if self.articles.contains_key(&target) {
+                return Ok(Some(Target::Article(target)));
+            } else {
+                bail!("Link {dest:?} points to missing article");
+            }
+        }
+
+        match target.extension() {
+            Some(_) if self.assets.contains(&target) => Ok(Some(Target::Asset(target))),
+            Some(_) => Ok(None),
+            None => {
+                if !path.ends_with('/') && self.articles.contains_key(&target) {
+                    Ok(Some(Target::Article(target)))
+                } else if self.directories.contains(&target) {
+                    Ok(Some(Target::Directory(target)))
+                } else if target.parent() == Some(Path::new("tags"))
+                    && self
+                        .tags
+                        .contains(&*target.file_name().unwrap().to_string_lossy())
+                {
+                    Ok(Some(Target::Tag(target)))
+                } else {
+                    bail!("Link {dest:?} points to missing article or directory");
+                }
+            }
+        }
+    }
+
+    /// Relpaths of articles linked from `metadata`, by links or wiki links.
+    /// Broken links are ignored here, as they are reported while rendering.
+    pub fn linked_articles(&self, metadata: &ArticleMetadata) -> HashSet<PathBuf> {
+        let mut linked = HashSet::new();
+        let mut in_code_block = false;
+        // `[[` and `]]` are split into separate text events otherwise
+        for event in TextMergeStream::new(Parser::new_ext(&metadata.body, markdown_options())) {
+            match event {
+                Event::Start(Tag::Link {
+                    link_type,
+                    dest_url,
+                    ..
+                }) if link_type != LinkType::Email => {
+                    if let Ok(Some(Target::Article(relpath))) =
+                        self.resolve_target(&metadata.relpath, &dest_url)
+                    {
+                        linked.insert(relpath);
+                    }
+                }
+                Event::Start(Tag::CodeBlock(_)) => in_code_block = true,
+                Event::End(TagEnd::CodeBlock) => in_code_block = false,
+                Event::Text(text) if !in_code_block => {
+                    for caps in WIKI_LINK_PATTERN.captures_iter(&text) {
+                        if let Ok(relpath) = self.resolve_wiki_link(&caps["target"]) {
+                            linked.insert(relpath.to_path_buf());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        linked.remove(&metadata.relpath);
+
+        linked
+    }
+
+    /// Finds the article of `[[target]]` by its path from `article_dir`, or by its title.
+    pub fn resolve_wiki_link(&self, target: &str) -> anyhow::Result<&Path> {
+        let target = target.trim();
+        let path = normalize(Path::new(target.trim_start_matches('/'))).map(|p| {
+            if Context::instance().config.articles.is_article(&p) {
+                p.with_extension("")
+            } else {
+                p
+            }
+        });
+        if let Some((relpath, _)) = path.and_then(|p| self.articles.get_key_value(&p)) {
+            return Ok(relpath);
+        }
+
+        match self.titles.get(target).map(Vec::as_slice) {
+            Some([relpath]) => Ok(relpath),
+            Some(relpaths) => bail!("Wiki link [[{target}]] is ambiguous: {relpaths:?}"),
+            None => bail!("Wiki link [[{target}]] points to missing article"),
+        }
+    }
+
+    pub fn title(&self, relpath: &Path) -> &str {
+        self.articles.get(relpath).map_or("", String::as_str)
+    }
+}
+
+/// Resolves `.` and `..` without touching the filesystem. `None` if it goes above the root.
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut result = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::Normal(c) => result.push(c),
+            Component::ParentDir => {
+                if !result.pop() {
+                    return None;
+                }
+            }
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+
+    Some(result)
+}
+
+fn url_path(relpath: &Path) -> String {
+    relpath
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+pub(super) fn article_url(relpath: &Path) -> String {
+    format!("/{}.html", url_path(relpath))
+}
+
+pub(super) fn asset_url(relpath: &Path) -> String {
+    format!("/{}", url_path(relpath))
+}
+
+fn directory_url(relpath: &Path) -> String {
+    if relpath.as_os_str().is_empty() {
+        "/".to_string()
+    } else {
+        format!("/{}/", url_path(relpath))
+    }
+}
+
+/// `<a>` for a wiki link, labeled with the title of the article unless `label` is given.
+pub(super) fn render_wiki_link(target: &str, label: Option<&str>) -> anyhow::Result<String> {
+    let index = ArticleIndex::instance();
+    let relpath = index.resolve_wiki_link(target)?;
+    let label = label.unwrap_or(match index.title(relpath) {
+        "" => target,
+        title => title,
+    });
+    Ok(html! {
+        a href=(article_url(relpath)) { (label) }
+    }
+    .into_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::init_context;
+
+    #[test]
+    fn finds_linked_articles() {
+        init_context();
+        let articles = [
+            ("a", "A"),
+            ("b", "B"),
+            ("dir/c", "C"),
+            ("d", "D"),
+            ("e", "E"),
+        ]
+        .map(|(relpath, title)| (PathBuf::from(relpath), title.to_string()));
+        let index = ArticleIndex {
+            titles: articles
+                .iter()
+                .map(|(relpath, title)| (title.clone(), vec![relpath.clone()]))
+                .collect(),
+            articles: articles.into(),
+            ..ArticleIndex::default()
+        };
+        let mut metadata = ArticleMetadata::new(std::fs::metadata(".").unwrap());
+        metadata.relpath = PathBuf::from("a");
+        metadata.body = "[[B]], [[dir/c.md|label]] and [D](d.md) link to others, \
+            and [[A]] to itself.\n\n```\n[[E]]\n```\n\n`[[E]]` [[Missing]] [F](f.md)\n"
+            .to_string();
+
+        let mut linked: Vec<_> = index.linked_articles(&metadata).into_iter().collect();
+        linked.sort();
+        assert_eq!(linked, ["b", "d", "dir/c"].map(PathBuf::from));
+    }
+
+    fn index() -> ArticleIndex {
+        init_context();
+        let articles = [("a", "A"), ("dir/c", "C"), ("dir/d", "A")]
+            .map(|(relpath, title)| (PathBuf::from(relpath), title.to_string()));
+        let mut titles: HashMap<String, Vec<PathBuf>> = HashMap::new();
+        for (relpath, title) in articles.iter() {
+            titles
+                .entry(title.clone())
+                .or_default()
+                .push(relpath.clone());
+        }
+        ArticleIndex {
+            articles: articles.into(),
+            directories: HashSet::from(["dir".into()]),
+            tags: HashSet::from(["rust".to_string()]),
+            assets: HashSet::from(["dir/img.png".into()]),
+            titles,
+        }
+    }
+
+    #[test]
+    fn resolves_internal_links() {
+        let index = index();
+        let resolve = |dest| index.resolve_link(Path::new("dir/c"), dest);
+
+        for (dest, url) in [
+            ("../a.md", "/a.html"),
+            ("/a.md#section", "/a.html#section"),
+            ("d", "/dir/d.html"),
+            ("./d.md?q", "/dir/d.html?q"),
+            ("/dir/", "/dir/"),
+            ("/tags/rust", "/tags/rust.html"),
+        ] {
+            assert_eq!(resolve(dest).unwrap().as_deref(), Some(url), "{dest}");
+        }
+        for external in [
+            "https://example.com/a.md",
+            "#top",
+            "//example.com",
+            "mailto:a@b",
+        ] {
+            assert_eq!(resolve(external).unwrap(), None, "{external}");
+        }
+        for broken in ["../missing.md", "../missing", "../../a.md", "/tags/go"] {
+            assert!(resolve(broken).is_err(), "{broken}");
+        }
+    }
+
+    #[test]
+    fn resolves_wiki_links_by_path_or_title() {
+        let index = index();
+        assert_eq!(index.resolve_wiki_link("C").unwrap(), Path::new("dir/c"));
+        assert_eq!(
+            index.resolve_wiki_link(" /dir/c.md ").unwrap(),
+            Path::new("dir/c")
+        );
+        assert_eq!(index.resolve_wiki_link("a").unwrap(), Path::new("a"));
+        assert!(index
+            .resolve_wiki_link("A")
+            .unwrap_err()
+            .to_string()
+            .contains("ambiguous"));
+        assert!(index.resolve_wiki_link("Missing").is_err());
+    }
+
+    #[test]
+    fn resolves_links_to_assets() {
+        let index = index();
+        let from = Path::new("dir/c");
+        assert_eq!(
+            index.resolve_link(from, "img.png#x").unwrap().as_deref(),
+            Some("/dir/img.png#x")
+        );
+        assert_eq!(
+            index.resolve_asset(from, "/dir/img.png"),
+            Some(PathBuf::from("dir/img.png"))
+        );
+        // files not copied are left as they are
+        assert_eq!(index.resolve_link(from, "other.pdf").unwrap(), None);
+        assert_eq!(index.resolve_asset(from, "other.pdf"), None);
+        assert_eq!(index.resolve_asset(from, "d.md"), None);
+        assert_eq!(
+            index.resolve_asset(from, "https://example.com/img.png"),
+            None
+        );
+    }
+}
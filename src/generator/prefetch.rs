@@ -0,0 +1,650 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use pulldown_cmark::{Event, LinkType, Parser, Tag};
+
+use crate::{
+    cache::{CacheEntry, FetchResult, OgpEntries},
+    config::OgpConfig,
+    ogp::OgpFetcher,
+};
+
+use super::{
+    card::{fetch_entry, needs_refetch, save_thumbnail, CardImage},
+    data::ArticleMetadata,
+    markdown_options,
+};
+
+/// URLs of autolinks in `metadata`, which are rendered as cards.
+pub(super) fn autolinks(metadata: &ArticleMetadata) -> Vec<String> {
+    Parser::new_ext(&metadata.body, markdown_options())
+        .filter_map(|e| match e {
+            Event::Start(Tag::Link {
+                link_type: LinkType::Autolink,
+                dest_url,
+                ..
+            }) => Some(dest_url.into_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// URLs in `urls` which are not in `cache` at all, without duplicates.
+pub(super) fn uncached(
+    urls: impl IntoIterator<Item = String>,
+    cache: &Mutex<OgpEntries>,
+) -> Vec<String> {
+    filter_urls(urls, |url| !cache.lock().unwrap().contains_key(url))
+}
+
+/// URLs in `urls` which are not in `cache` or need fetching again, without duplicates.
+fn stale(
+    urls: impl IntoIterator<Item = String>,
+    cache: &Mutex<OgpEntries>,
+    config: &OgpConfig,
+    now: DateTime<Utc>,
+) -> Vec<String> {
+    filter_urls(urls, |url| {
+        cache
+            .lock()
+            .unwrap()
+            .get(url)
+            .is_none_or(|c| needs_refetch(c, config, now))
+    })
+}
+
+fn filter_urls(urls: impl IntoIterator<Item = String>, pred: impl Fn(&str) -> bool) -> Vec<String> {
+    let mut result = vec![];
+    for url in urls {
+        if pred(&url) && !result.contains(&url) {
+            result.push(url);
+        }
+    }
+
+    result
+}
+
+/// `example.com` of `https://example.com/path`
+fn host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = &rest[..rest.find(['/', '?', '#']).unwrap_or(rest.len())];
+    authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host)
+}
+
+/// When requests may be sent, shared by fetching pages and images
+#[derive(Debug)]
+struct Schedule {
+    /// when the whole fetching gives up
+    deadline: Instant,
+    /// when the next request to each host may be sent
+    next_at: HashMap<String, Instant>,
+}
+
+impl Schedule {
+    fn new(config: &OgpConfig) -> Self {
+        Self {
+            deadline: Instant::now() + Duration::from_secs(config.timeout),
+            next_at: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HostQueue {
+    urls: VecDeque<String>,
+    running: usize,
+    /// when the next request to the host may be sent
+    next_at: Instant,
+}
+
+/// Fetches OGP info of `urls` not in `cache` yet or stale, and stores them into it.
+/// Thumbnails and favicons not downloaded yet are also downloaded if configured, without fetching the pages again.
+/// URLs left when `config.timeout` has passed are not cached, and shown as plain links.
+pub(super) fn prefetch(
+    urls: impl IntoIterator<Item = String>,
+    cache: &Mutex<OgpEntries>,
+    fetcher: &dyn OgpFetcher,
+    config: &OgpConfig,
+    thumbnail_dir: &Path,
+) {
+    let mut schedule = Schedule::new(config);
+    let urls = filter_urls(urls, |_| true);
+    fetch_pages(
+        stale(urls.clone(), cache, config, Utc::now()),
+        cache,
+        fetcher,
+        config,
+        &mut schedule,
+    );
+    if config.thumbnails {
+        save_images(&urls, cache, fetcher, config, thumbnail_dir, &mut schedule);
+    }
+}
+
+/// Fetches OGP info of `urls` regardless of `cache`, and stores them into it.
+/// Successful entries are kept if fetching fails again.
+pub(crate) fn fetch_all(
+    urls: Vec<String>,
+    cache: &Mutex<OgpEntries>,
+    fetcher: &dyn OgpFetcher,
+    config: &OgpConfig,
+    thumbnail_dir: &Path,
+) {
+    let mut schedule = Schedule::new(config);
+    fetch_pages(urls.clone(), cache, fetcher, config, &mut schedule);
+    if config.thumbnails {
+        save_images(&urls, cache, fetcher, config, thumbnail_dir, &mut schedule);
+    }
+}
+
+fn fetch_pages(
+    urls: Vec<String>,
+    cache: &Mutex<OgpEntries>,
+    fetcher: &dyn OgpFetcher,
+    config: &OgpConfig,
+    schedule: &mut Schedule,
+) {
+    if urls.is_empty() {
+        return;
+    }
+    let start = Instant::now();
+    let left = for_each_per_host(urls, config, schedule, "OGP info", |url| {
+        let entry = fetch_entry(url, fetcher, config, Utc::now());
+        let mut cache = cache.lock().unwrap();
+        match &entry.result {
+            FetchResult::Error(error) if cache.get(url).is_some_and(|c| !c.is_failure()) => {
+                warn!("Keeping stale OGP info of {url}: {error}");
+            }
+            _ => {
+                cache.insert(url.to_string(), entry);
+            }
+        }
+    });
+    if left > 0 {
+        warn!(
+            "Gave up fetching OGP info of {left} URLs in {}s",
+            config.timeout
+        );
+    }
+    info!("Fetched OGP info in {:.1}s", start.elapsed().as_secs_f64());
+}
+
+/// Downloads thumbnails and favicons of `urls` which are not in `thumbnail_dir` yet, and records them in `cache`.
+/// Each image is downloaded once even if several pages share it, such as the favicon of a site.
+/// Thumbnails which could not be saved are hotlinked, while such favicons are left out.
+fn save_images(
+    urls: &[String],
+    cache: &Mutex<OgpEntries>,
+    fetcher: &dyn OgpFetcher,
+    config: &OgpConfig,
+    thumbnail_dir: &Path,
+    schedule: &mut Schedule,
+) {
+    let mut heights = HashMap::new();
+    {
+        let mut cache = cache.lock().unwrap();
+        // images downloaded for other pages
+        let mut saved = HashMap::new();
+        for info in cache.values().filter_map(CacheEntry::info) {
+            for image in CardImage::ALL {
+                if let (Some(url), Some(name)) = image.fields(info) {
+                    if thumbnail_dir.join(name).exists() {
+                        saved.insert(url.to_string(), name.to_string());
+                    }
+                }
+            }
+        }
+        for url in urls {
+            let Some(FetchResult::Info(info)) = cache.get_mut(url).map(|c| &mut c.result) else {
+                continue;
+            };
+            for image in CardImage::ALL {
+                let Some(image_url) = image.missing(info, thumbnail_dir).map(String::from) else {
+                    continue;
+                };
+                match saved.get(&image_url) {
+                    Some(name) => *image.fields_mut(info).1 = Some(name.clone()),
+                    None => {
+                        heights.entry(image_url).or_insert(image.height(config));
+                    }
+                }
+            }
+        }
+    }
+    if heights.is_empty() {
+        return;
+    }
+
+    let results = Mutex::new(HashMap::new());
+    let image_urls = heights.keys().cloned().collect();
+    let left = for_each_per_host(image_urls, config, schedule, "images", |image_url| {
+        let result = save_thumbnail(image_url, fetcher, thumbnail_dir, heights[image_url]);
+        results
+            .lock()
+            .unwrap()
+            .insert(image_url.to_string(), result);
+    });
+    if left > 0 {
+        warn!("Gave up downloading {left} images, which are downloaded in the next build");
+    }
+
+    let results = results.into_inner().unwrap();
+    let mut cache = cache.lock().unwrap();
+    for url in urls {
+        let Some(FetchResult::Info(info)) = cache.get_mut(url).map(|c| &mut c.result) else {
+            continue;
+        };
+        for image in CardImage::ALL {
+            let (image_url, name) = image.fields_mut(info);
+            match image_url.as_ref().and_then(|u| results.get(u)) {
+                Some(Ok(saved)) => *name = Some(saved.clone()),
+                Some(Err(e)) if image == CardImage::Thumbnail => {
+                    warn!("Hotlinking thumbnail of {url}, which could not be saved: {e:#}");
+                }
+                // favicons are decoration, and `/favicon.ico` may not exist
+                Some(Err(e)) => {
+                    debug!("leaving out the favicon of {url}: {e:#}");
+                    *image_url = None;
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+/// Calls `job` with each of `urls` until `deadline`, and returns the number of URLs left.
+/// Requests to different hosts run concurrently, while ones to the same host are limited and spaced.
+fn for_each_per_host(
+    urls: Vec<String>,
+    config: &OgpConfig,
+    schedule: &mut Schedule,
+    what: &str,
+    job: impl Fn(&str) + Sync,
+) -> usize {
+    let start = Instant::now();
+    let deadline = schedule.deadline;
+    let interval = Duration::from_secs(config.per_host_interval);
+
+    let total = urls.len();
+    let mut hosts: HashMap<String, HostQueue> = HashMap::new();
+    for url in urls {
+        let host = host(&url);
+        hosts
+            .entry(host.to_string())
+            .or_insert_with(|| HostQueue {
+                urls: VecDeque::new(),
+                running: 0,
+                next_at: schedule.next_at.get(host).copied().unwrap_or(start),
+            })
+            .urls
+            .push_back(url);
+    }
+    info!("Fetching {what} of {total} URLs from {} hosts", hosts.len());
+
+    let hosts = Mutex::new(hosts);
+    let changed = Condvar::new();
+    let worker = || loop {
+        let mut queues = hosts.lock().unwrap();
+        let url = loop {
+            let now = Instant::now();
+            if now >= deadline || queues.values().all(|h| h.urls.is_empty()) {
+                return;
+            }
+            let ready = queues.values_mut().find(|h| {
+                !h.urls.is_empty() && h.running < config.per_host_concurrency && h.next_at <= now
+            });
+            if let Some(queue) = ready {
+                queue.running += 1;
+                queue.next_at = now + interval;
+                break queue.urls.pop_front().unwrap();
+            }
+            // wait for a host to become ready, or for a running request to finish
+            let wake_at = queues
+                .values()
+                .filter(|h| !h.urls.is_empty() && h.running < config.per_host_concurrency)
+                .map(|h| h.next_at)
+                .min()
+                .unwrap_or(deadline)
+                .min(deadline);
+            queues = changed
+                .wait_timeout(queues, wake_at.saturating_duration_since(now))
+                .unwrap()
+                .0;
+        };
+        drop(queues);
+
+        debug!("fetching {url}...");
+        job(&url);
+
+        hosts.lock().unwrap().get_mut(host(&url)).unwrap().running -= 1;
+        changed.notify_all();
+    };
+    std::thread::scope(|s| {
+        for _ in 0..config.concurrency.max(1) {
+            s.spawn(worker);
+        }
+    });
+
+    let mut left = 0;
+    for (host, queue) in hosts.into_inner().unwrap() {
+        left += queue.urls.len();
+        schedule.next_at.insert(host, queue.next_at);
+    }
+
+    left
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::{CacheEntry, OgpInfo},
+        ogp::StubFetcher,
+        test_utils::{failure, ogp_config, png, temp_dir, NO_THUMBNAILS},
+    };
+
+    const PAGE: &str = r#"<html><head>
+        <meta property="og:type" content="website">
+        <meta property="og:title" content="Title">
+        <meta property="og:url" content="https://example.com/">
+        <meta property="og:image" content="https://example.com/thumb.png">
+        </head></html>"#;
+
+    #[test]
+    fn extracts_host() {
+        assert_eq!(host("https://example.com/a?b#c"), "example.com");
+        assert_eq!(host("http://user@example.com:8080"), "example.com:8080");
+        assert_eq!(host("https://example.com?q"), "example.com");
+    }
+
+    #[test]
+    fn lists_uncached_and_stale_urls() {
+        let cache = Mutex::new(OgpEntries::from([
+            (
+                "https://a.example/old".to_string(),
+                failure(Some("2000-01-01T00:00:00Z")),
+            ),
+            (
+                "https://a.example/new".to_string(),
+                failure(Some(&Utc::now().to_rfc3339())),
+            ),
+        ]));
+        let urls = [
+            "https://a.example/old",
+            "https://a.example/new",
+            "https://a.example/uncached",
+            "https://a.example/uncached",
+        ];
+
+        assert_eq!(
+            uncached(urls.map(String::from), &cache),
+            ["https://a.example/uncached"]
+        );
+        assert_eq!(
+            stale(urls.map(String::from), &cache, &ogp_config(), Utc::now()),
+            ["https://a.example/old", "https://a.example/uncached"]
+        );
+    }
+
+    #[test]
+    fn fetches_only_uncached_urls_once() {
+        let cache = Mutex::new(OgpEntries::from([(
+            "https://a.example/cached".to_string(),
+            failure(Some(&Utc::now().to_rfc3339())),
+        )]));
+        let fetcher = StubFetcher::default()
+            .with_page("https://a.example/1", PAGE)
+            .with_page("https://b.example/1", PAGE);
+        let urls = [
+            "https://a.example/1",
+            "https://a.example/cached",
+            "https://b.example/1",
+            "https://a.example/1",
+            "https://c.example/missing",
+        ];
+
+        prefetch(
+            urls.map(String::from),
+            &cache,
+            &fetcher,
+            &ogp_config(),
+            Path::new(NO_THUMBNAILS),
+        );
+
+        let mut requested = fetcher.requested.lock().unwrap().clone();
+        requested.sort();
+        assert_eq!(
+            requested,
+            [
+                "https://a.example/1",
+                "https://b.example/1",
+                "https://c.example/missing"
+            ]
+        );
+        let cache = cache.lock().unwrap();
+        assert_eq!(cache["https://a.example/1"].info().unwrap().title, "Title");
+        assert!(cache["https://c.example/missing"].is_failure());
+    }
+
+    #[test]
+    fn keeps_stale_entry_if_fetching_fails() {
+        let url = "https://a.example/1";
+        let old = CacheEntry {
+            fetched_at: Some("2000-01-01T00:00:00Z".parse().unwrap()),
+            status: Some(200),
+            result: FetchResult::Info(Box::new(OgpInfo {
+                og_type: "website".to_string(),
+                title: "Old".to_string(),
+                url: url.to_string(),
+                thumb_url: Some("https://a.example/t.png".to_string()),
+                ..OgpInfo::default()
+            })),
+        };
+        let cache = Mutex::new(OgpEntries::from([(url.to_string(), old.clone())]));
+        let fetcher = StubFetcher::default();
+
+        prefetch(
+            [url.to_string()],
+            &cache,
+            &fetcher,
+            &ogp_config(),
+            Path::new(NO_THUMBNAILS),
+        );
+
+        assert_eq!(*fetcher.requested.lock().unwrap(), [url]);
+        assert_eq!(cache.lock().unwrap()[url], old);
+    }
+
+    #[test]
+    fn saves_thumbnails_without_refetching_pages() {
+        let dir = temp_dir("backfill");
+        // from an older version, which hotlinked thumbnails
+        let legacy = |thumb_url: &str| CacheEntry {
+            fetched_at: None,
+            status: None,
+            result: FetchResult::Info(Box::new(OgpInfo {
+                og_type: "website".to_string(),
+                title: "Legacy".to_string(),
+                thumb_url: Some(thumb_url.to_string()),
+                ..OgpInfo::default()
+            })),
+        };
+        let cache = Mutex::new(OgpEntries::from([
+            (
+                "https://a.example/1".to_string(),
+                legacy("https://a.example/t.png"),
+            ),
+            (
+                "https://a.example/2".to_string(),
+                legacy("https://a.example/t.png"),
+            ),
+            (
+                "https://a.example/3".to_string(),
+                legacy("https://a.example/broken.png"),
+            ),
+        ]));
+        let fetcher = StubFetcher::default().with_image("https://a.example/t.png", png(10, 10));
+        let config = OgpConfig {
+            thumbnails: true,
+            ..ogp_config()
+        };
+
+        prefetch(
+            [
+                "https://a.example/1",
+                "https://a.example/2",
+                "https://a.example/3",
+            ]
+            .map(String::from),
+            &cache,
+            &fetcher,
+            &config,
+            &dir,
+        );
+
+        // each image is downloaded once, and no page is fetched
+        let mut requested = fetcher.requested.lock().unwrap().clone();
+        requested.sort();
+        assert_eq!(
+            requested,
+            ["https://a.example/broken.png", "https://a.example/t.png"]
+        );
+        let cache = cache.lock().unwrap();
+        let name = cache["https://a.example/1"]
+            .info()
+            .unwrap()
+            .thumbnail
+            .clone();
+        assert!(dir.join(name.as_ref().unwrap()).exists());
+        assert_eq!(cache["https://a.example/2"].info().unwrap().thumbnail, name);
+        // hotlinked, and tried again on the next build
+        assert_eq!(cache["https://a.example/3"].info().unwrap().thumbnail, None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn shares_favicons_between_pages() {
+        let dir = temp_dir("favicons");
+        let mut ico = vec![];
+        image::DynamicImage::new_rgba8(64, 64)
+            .write_to(&mut std::io::Cursor::new(&mut ico), image::ImageFormat::Ico)
+            .unwrap();
+        let page = "<html><head><title>Title</title></head></html>";
+        let fetcher = StubFetcher::default()
+            .with_page("https://a.example/1", page)
+            .with_page("https://a.example/2", page)
+            .with_page("https://a.example/3", page)
+            .with_page("https://b.example/1", page)
+            .with_image("https://a.example/favicon.ico", ico);
+        let config = OgpConfig {
+            thumbnails: true,
+            ..ogp_config()
+        };
+        let cache = Mutex::new(OgpEntries::new());
+
+        prefetch(
+            [
+                "https://a.example/1",
+                "https://a.example/2",
+                "https://b.example/1",
+            ]
+            .map(String::from),
+            &cache,
+            &fetcher,
+            &config,
+            &dir,
+        );
+        // another page of the site in a later build
+        prefetch(
+            ["https://a.example/3".to_string()],
+            &cache,
+            &fetcher,
+            &config,
+            &dir,
+        );
+
+        let requested = fetcher.requested.lock().unwrap().clone();
+        let count = |url| requested.iter().filter(|r| *r == url).count();
+        assert_eq!(count("https://a.example/favicon.ico"), 1);
+        assert_eq!(count("https://b.example/favicon.ico"), 1);
+        let cache = cache.lock().unwrap();
+        let icon = |url: &str| cache[url].info().unwrap().icon.clone();
+        let name = icon("https://a.example/1").unwrap();
+        assert_eq!(
+            image::open(dir.join(&name)).unwrap().height(),
+            CardImage::Icon.height(&config)
+        );
+        assert_eq!(icon("https://a.example/2").as_ref(), Some(&name));
+        assert_eq!(icon("https://a.example/3").as_ref(), Some(&name));
+        // `/favicon.ico` which does not exist is left out
+        assert_eq!(cache["https://b.example/1"].info().unwrap().icon_url, None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn spaces_requests_to_same_host() {
+        let cache = Mutex::new(OgpEntries::new());
+        let fetcher = StubFetcher::default();
+        let config = OgpConfig {
+            per_host_interval: 1,
+            ..OgpConfig::default()
+        };
+
+        prefetch(
+            [
+                "https://a.example/1",
+                "https://a.example/2",
+                "https://b.example/1",
+            ]
+            .map(String::from),
+            &cache,
+            &fetcher,
+            &config,
+            Path::new(NO_THUMBNAILS),
+        );
+
+        let at = |url| fetcher.first_requested_at(url).unwrap();
+        assert!(
+            at("https://a.example/2").duration_since(at("https://a.example/1"))
+                >= Duration::from_secs(1)
+        );
+        // b.example does not wait for a.example
+        assert!(at("https://b.example/1") < at("https://a.example/2"));
+        assert_eq!(cache.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn leaves_urls_uncached_after_timeout() {
+        let cache = Mutex::new(OgpEntries::new());
+        let fetcher = StubFetcher::default();
+        // the second request would wait far longer than the timeout
+        let config = OgpConfig {
+            per_host_interval: 600,
+            timeout: 2,
+            ..OgpConfig::default()
+        };
+
+        prefetch(
+            ["https://a.example/1", "https://a.example/2"].map(String::from),
+            &cache,
+            &fetcher,
+            &config,
+            Path::new(NO_THUMBNAILS),
+        );
+
+        assert_eq!(*fetcher.requested.lock().unwrap(), ["https://a.example/1"]);
+        let cache = cache.lock().unwrap();
+        assert!(cache.contains_key("https://a.example/1"));
+        assert!(!cache.contains_key("https://a.example/2"));
+    }
+}
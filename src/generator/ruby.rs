@@ -0,0 +1,112 @@
+use std::sync::LazyLock;
+
+use maud::html;
+use regex::{Captures, Regex};
+
+/// `{漢字|かんじ}`, `｜漢字《かんじ》` (or with `|`), and `漢字《かんじ》` for a run of kanji
+static RUBY_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(concat!(
+        r"\{(?<b1>[^{}|\n]+)\|(?<r1>[^{}|\n]+)\}",
+        r"|[｜|](?<b2>[^｜|《》\n]+)《(?<r2>[^《》\n]+)》",
+        r"|(?<b3>[\p{Han}々〆ヵヶ]+)《(?<r3>[^《》\n]+)》",
+    ))
+    .unwrap()
+});
+
+fn base_and_reading<'t>(caps: &Captures<'t>) -> (&'t str, &'t str) {
+    let group = |names: [&str; 3]| {
+        names
+            .into_iter()
+            .find_map(|n| caps.name(n))
+            .unwrap()
+            .as_str()
+    };
+    (group(["b1", "b2", "b3"]), group(["r1", "r2", "r3"]))
+}
+
+/// Converts ruby notations in `text` into `<ruby>`, escaping the rest.
+/// Returns `None` if there is no ruby.
+pub(super) fn render_ruby(text: &str) -> Option<String> {
+    if !RUBY_PATTERN.is_match(text) {
+        return None;
+    }
+
+    let mut rendered = String::new();
+    let mut last = 0;
+    for caps in RUBY_PATTERN.captures_iter(text) {
+        let whole = caps.get(0).unwrap();
+        let (base, reading) = base_and_reading(&caps);
+        rendered.push_str(
+            &html! {
+                (text[last..whole.start()])
+                ruby { (base) rp { "(" } rt { (reading) } rp { ")" } }
+            }
+            .into_string(),
+        );
+        last = whole.end();
+    }
+    rendered.push_str(&html! { (text[last..]) }.into_string());
+
+    Some(rendered)
+}
+
+/// `text` with ruby notations replaced by their bases, for plain-text uses such as headings in table of contents.
+pub(super) fn strip_ruby(text: &str) -> String {
+    RUBY_PATTERN
+        .replace_all(text, |caps: &Captures| base_and_reading(caps).0.to_string())
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        generator::{data::ArticleMetadata, heading::Slugger, render_markdown},
+        test_utils::init_context,
+    };
+
+    #[test]
+    fn renders_ruby_notations() {
+        let ruby = |base: &str, reading: &str| {
+            format!("<ruby>{base}<rp>(</rp><rt>{reading}</rt><rp>)</rp></ruby>")
+        };
+        assert_eq!(
+            render_ruby("{漢字|かんじ}と<b>").unwrap(),
+            format!("{}と&lt;b&gt;", ruby("漢字", "かんじ"))
+        );
+        assert_eq!(
+            render_ruby("この｜東京タワー《とうきょうタワー》").unwrap(),
+            format!("この{}", ruby("東京タワー", "とうきょうタワー"))
+        );
+        // a run of kanji just before the reading
+        assert_eq!(
+            render_ruby("ひらがな漢字《かんじ》").unwrap(),
+            format!("ひらがな{}", ruby("漢字", "かんじ"))
+        );
+        assert_eq!(render_ruby("no ruby《here》"), None);
+    }
+
+    #[test]
+    fn strips_ruby_into_bases() {
+        assert_eq!(
+            strip_ruby("{漢字|かんじ}の|見出し《みだし》"),
+            "漢字の見出し"
+        );
+    }
+
+    #[test]
+    fn leaves_ruby_in_code() {
+        init_context();
+        let mut metadata = ArticleMetadata::new(std::fs::metadata(".").unwrap());
+        metadata.body = "`{漢字|かんじ}` {漢字|かんじ}\n\n```\n漢字《かんじ》\n```\n".to_string();
+
+        let (html, _) =
+            render_markdown(&metadata, &metadata.body, 0, &mut Slugger::default()).unwrap();
+        assert!(
+            html.contains("<code>{漢字|かんじ}</code> <ruby>漢字"),
+            "{html}"
+        );
+        assert!(html.contains("漢字《かんじ》"), "{html}");
+        assert_eq!(html.matches("<ruby>").count(), 1);
+    }
+}
@@ -0,0 +1,268 @@
+use std::{ops::Range, sync::LazyLock};
+
+use anyhow::{bail, Context as _};
+use pulldown_cmark::{Event, Parser, Tag};
+use regex::Regex;
+use serde_json::{Map, Value};
+
+use crate::context::Context;
+
+use super::{
+    data::ArticleMetadata,
+    heading::Slugger,
+    markdown_options, render_markdown,
+    utils::{location, split_attributes},
+};
+
+/// `{{< name key="value" >}}` or `{{< /name >}}`
+static SHORTCODE_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\{\{<\s*(?<close>/)?\s*(?<name>[\w-]+)(?<args>[^\n]*?)\s*>\}\}").unwrap()
+});
+
+#[derive(Debug)]
+struct Shortcode<'a> {
+    name: &'a str,
+    args: &'a str,
+    range: Range<usize>,
+    /// content between opening and closing tags
+    inner: Option<Range<usize>>,
+}
+
+/// Markdown whose shortcodes are replaced with placeholders
+pub(super) struct ExpandedBody {
+    pub body: String,
+    /// rendered shortcodes, the `i`th of which replaces `placeholder(i)` in HTML
+    pub shortcodes: Vec<String>,
+    /// `(end in body, end in the original)` of each placeholder
+    placeholder_ends: Vec<(usize, usize)>,
+}
+
+impl ExpandedBody {
+    /// Offset in the original Markdown corresponding to `offset` in `body`
+    pub fn original_offset(&self, offset: usize) -> usize {
+        match self
+            .placeholder_ends
+            .iter()
+            .rev()
+            .find(|(end, _)| *end <= offset)
+        {
+            Some((end, original_end)) => original_end + (offset - end),
+            None => offset,
+        }
+    }
+}
+
+/// Comment left in Markdown in place of the `i`th shortcode, so that the rendered one is not parsed as Markdown.
+pub(super) fn placeholder(i: usize) -> String {
+    format!("<!-- shortcode:{i} -->")
+}
+
+/// Replaces shortcodes in `body` (a part of `metadata.body` starting at `body_offset`) with placeholders.
+pub(super) fn expand_shortcodes(
+    metadata: &ArticleMetadata,
+    body: &str,
+    body_offset: usize,
+    slugger: &mut Slugger,
+) -> anyhow::Result<ExpandedBody> {
+    let ctx = Context::instance();
+
+    // shortcodes in code are shown as they are
+    let code_ranges: Vec<_> = Parser::new_ext(body, markdown_options())
+        .into_offset_iter()
+        .filter_map(|(e, range)| match e {
+            Event::Code(_) | Event::Start(Tag::CodeBlock(_)) => Some(range),
+            _ => None,
+        })
+        .collect();
+
+    let mut shortcodes: Vec<Shortcode> = vec![];
+    let mut opening = vec![]; // indices of shortcodes which may have closing tags
+    for caps in SHORTCODE_PATTERN.captures_iter(body) {
+        let range = caps.get(0).unwrap().range();
+        if code_ranges.iter().any(|r| r.contains(&range.start)) {
+            continue;
+        }
+        let name = caps.name("name").unwrap().as_str();
+        if caps.name("close").is_none() {
+            opening.push(shortcodes.len());
+            shortcodes.push(Shortcode {
+                name,
+                args: caps.name("args").unwrap().as_str(),
+                range,
+                inner: None,
+            });
+            continue;
+        }
+
+        let Some(pos) = opening.iter().rposition(|&i| shortcodes[i].name == name) else {
+            bail!(
+                "{}: closing shortcode {name:?} without opening one",
+                location(metadata, body_offset + range.start)
+            );
+        };
+        let shortcode = &mut shortcodes[opening[pos]];
+        shortcode.inner = Some(shortcode.range.end..range.start);
+        shortcode.range.end = range.end;
+        opening.truncate(pos);
+    }
+
+    let mut replaced = String::new();
+    let mut rendered = vec![];
+    let mut placeholder_ends = vec![];
+    let mut last = 0;
+    for shortcode in shortcodes {
+        // nested ones are expanded while rendering the content of outer one
+        if shortcode.range.start < last {
+            continue;
+        }
+        let loc = location(metadata, body_offset + shortcode.range.start);
+        let template_name = format!("shortcodes/{}", shortcode.name);
+        if !ctx.shortcodes.has_template(&template_name) {
+            bail!("{loc}: unknown shortcode {:?}", shortcode.name);
+        }
+
+        let mut args = Map::new();
+        for (key, value) in split_attributes(shortcode.args).with_context(|| loc.clone())? {
+            let value = value.map_or(Value::Bool(true), Value::String);
+            if key == "inner" || args.insert(key.clone(), value).is_some() {
+                bail!("{loc}: invalid or duplicated argument {key:?}");
+            }
+        }
+        if let Some(inner) = shortcode.inner {
+            let (inner_html, _) = render_markdown(
+                metadata,
+                &body[inner.clone()],
+                body_offset + inner.start,
+                slugger,
+            )?;
+            args.insert("inner".to_string(), Value::String(inner_html));
+        }
+        if let Some(declared) = ctx.shortcode_args.get(&template_name) {
+            if let Some(key) = args.keys().find(|key| !declared.accepts(key)) {
+                bail!(
+                    "{loc}: unknown argument {key:?} of shortcode {:?}",
+                    shortcode.name
+                );
+            }
+            if let Some(key) = declared
+                .required
+                .iter()
+                .find(|key| !args.contains_key(*key))
+            {
+                bail!(
+                    "{loc}: missing argument {key:?} of shortcode {:?}",
+                    shortcode.name
+                );
+            }
+        }
+
+        rendered.push(
+            ctx.shortcodes
+                .render(&template_name, &args)
+                .with_context(|| {
+                    format!("{loc}: while rendering shortcode {:?}", shortcode.name)
+                })?,
+        );
+        replaced.push_str(&body[last..shortcode.range.start]);
+        replaced.push_str(&placeholder(rendered.len() - 1));
+        placeholder_ends.push((replaced.len(), shortcode.range.end));
+        last = shortcode.range.end;
+    }
+    replaced.push_str(&body[last..]);
+
+    Ok(ExpandedBody {
+        body: replaced,
+        shortcodes: rendered,
+        placeholder_ends,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::init_context;
+
+    fn metadata(body: &str) -> ArticleMetadata {
+        init_context();
+        let mut metadata = ArticleMetadata::new(std::fs::metadata(".").unwrap());
+        metadata.source_relpath = "a.md".into();
+        metadata.body = body.to_string();
+        metadata
+    }
+
+    fn expand(body: &str) -> anyhow::Result<ExpandedBody> {
+        expand_shortcodes(&metadata(body), body, 0, &mut Slugger::default())
+    }
+
+    #[test]
+    fn replaces_shortcodes_with_placeholders() {
+        let body =
+            "a {{< greet name=\"you\" >}} b `{{< greet >}}` {{< greet name=me greeting=Hi >}}";
+        let expanded = expand(body).unwrap();
+        assert_eq!(
+            expanded.body,
+            format!(
+                "a {} b `{{{{< greet >}}}}` {}",
+                placeholder(0),
+                placeholder(1)
+            )
+        );
+        assert_eq!(
+            expanded.shortcodes,
+            ["<p>Hello, you!</p>", "<p>Hi, me!</p>"]
+        );
+
+        let b = expanded.body.find(" b").unwrap();
+        assert_eq!(expanded.original_offset(b), body.find(" b").unwrap());
+        let second = expanded.body.find(&placeholder(1)).unwrap();
+        assert_eq!(expanded.original_offset(second), body.rfind("{{<").unwrap());
+    }
+
+    #[test]
+    fn rejects_unknown_shortcodes_and_arguments() {
+        let error = |body| expand(body).err().unwrap().to_string();
+        assert_eq!(
+            error("x\n\n{{< nope >}}"),
+            r#"a.md:3:1: unknown shortcode "nope""#
+        );
+        assert_eq!(
+            error("{{< greet name=you color=red >}}"),
+            r#"a.md:1:1: unknown argument "color" of shortcode "greet""#
+        );
+        assert_eq!(
+            error("x {{< greet greeting=Hi >}}"),
+            r#"a.md:1:3: missing argument "name" of shortcode "greet""#
+        );
+        assert_eq!(
+            error("{{< greet name=you >}}content{{< /greet >}}"),
+            r#"a.md:1:1: unknown argument "inner" of shortcode "greet""#
+        );
+    }
+
+    #[test]
+    fn gives_unique_ids_to_headings_in_shortcodes() {
+        let metadata = metadata("### Intro\n\n{{< box >}}\n### Intro\n{{< /box >}}\n");
+        let (html, headings) =
+            render_markdown(&metadata, &metadata.body, 0, &mut Slugger::default()).unwrap();
+        assert!(html.contains(r#"<div><h3 id="intro">Intro</h3>"#), "{html}");
+        assert_eq!(headings, [(3, "intro-1".to_string(), "Intro".to_string())]);
+    }
+
+    #[test]
+    fn renders_nested_shortcodes_in_content() {
+        let expanded = expand("{{< box >}}\n*a* {{< greet name=\"b c\" >}}\n{{< /box >}}").unwrap();
+        assert_eq!(expanded.body, placeholder(0));
+        assert_eq!(
+            expanded.shortcodes,
+            ["<div><p><em>a</em> <p>Hello, b c!</p></p>\n</div>"]
+        );
+    }
+
+    #[test]
+    fn rejects_unmatched_closing_tags() {
+        assert_eq!(
+            expand("a\n{{< /box >}}").err().unwrap().to_string(),
+            r#"a.md:2:1: closing shortcode "box" without opening one"#
+        );
+    }
+}
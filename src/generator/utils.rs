@@ -1,32 +1,90 @@
 use std::{borrow::Borrow, cmp::Ordering};
 
-use log::{debug, warn};
+use anyhow::{bail, Context as _};
+use log::warn;
 use maud::html;
-use pulldown_cmark::{Event, LinkType, Tag, TagEnd};
-use serde_json::{json, Value};
-use webpage::{Opengraph, OpengraphObject, Webpage, WebpageOptions};
-
-use crate::context::Context;
-
-use super::data::ArticleMetadata;
-
-pub(super) fn render_card(href: &str, og: &Opengraph) -> String {
-    // TODO: change element by og-type
-    html! {
-        a.og-href href=(href) {
-            span.og-card.{ "og-type-" (og.og_type) } {
-                span.og-text {
-                    span.og-title { (og.properties.get("title").unwrap()) }
-                    span.og-desc { (og.properties.get("description").unwrap_or(&" ".to_string())) }
-                    span.og-url { (og.properties.get("url").unwrap()) }
-                    }
-                span.og-image-wrap {
-                    img.og-image src=(og.images[0].url);
+use pulldown_cmark::{BlockQuoteKind, CodeBlockKind, Event, LinkType, Tag, TagEnd};
+
+use crate::{config::LineBreak, context::Context};
+
+use super::{
+    card::link_card,
+    code_block::{read_include, render_code_block, CodeBlockInfo},
+    data::ArticleMetadata,
+    diagram::{is_diagram, render_diagram},
+    heading::{demote, render_heading, Headings, PendingHeading, Slugger},
+    image::{render_image, PendingImage},
+    link::{render_wiki_link, ArticleIndex, WIKI_LINK_PATTERN},
+    ruby::{render_ruby, strip_ruby},
+};
+
+/// opening part of `<aside>` for `> [!NOTE]` style blockquote
+pub(super) fn render_admonition_start(kind: BlockQuoteKind) -> String {
+    let titles = &Context::instance().config.admonition;
+    let (class, title) = match kind {
+        BlockQuoteKind::Note => ("note", &titles.note),
+        BlockQuoteKind::Tip => ("tip", &titles.tip),
+        BlockQuoteKind::Important => ("important", &titles.important),
+        BlockQuoteKind::Warning => ("warning", &titles.warning),
+        BlockQuoteKind::Caution => ("caution", &titles.caution),
+    };
+    format!(
+        r#"<aside class="admonition admonition-{class}">{}"#,
+        html! { p.admonition-title { (title) } }.into_string()
+    )
+}
+
+/// split into `key` or `key=value` / `key="value"` pairs
+pub(super) fn split_attributes(info: &str) -> anyhow::Result<Vec<(String, Option<String>)>> {
+    let mut result = vec![];
+    let mut chars = info.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '=') {
+            key.push(c);
+        }
+        if chars.next_if_eq(&'=').is_none() {
+            result.push((key, None));
+            continue;
+        }
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => value.push(c),
+                    None => bail!("Unterminated quote in attributes: {info}"),
                 }
             }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                value.push(c);
+            }
         }
+        result.push((key, Some(value)));
     }
-    .into()
+
+    Ok(result)
+}
+
+/// `path:line:column` of `offset` in `metadata.body`
+pub(super) fn location(metadata: &ArticleMetadata, offset: usize) -> String {
+    let before = &metadata.body[..offset];
+    let line = metadata.body_line + before.matches('\n').count();
+    let column = before
+        .rsplit('\n')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .count()
+        + 1;
+    format!("{}:{line}:{column}", metadata.source_relpath.display())
 }
 
 pub(super) fn sort_article<T: Borrow<ArticleMetadata>>(a: &T, b: &T) -> Ordering {
@@ -38,141 +96,347 @@ pub(super) fn sort_article<T: Borrow<ArticleMetadata>>(a: &T, b: &T) -> Ordering
     }
 }
 
-pub(super) fn gen_parser_event_iterator() -> Box<dyn FnMut(Event) -> Event> {
+/// Reports a broken internal link at `offset` in `metadata.body`,
+/// as an error if `links.strict` is set, as a warning otherwise.
+fn report_broken_link(
+    metadata: &ArticleMetadata,
+    offset: usize,
+    error: anyhow::Error,
+) -> anyhow::Result<()> {
+    let loc = location(metadata, offset);
+    if Context::instance().config.links.strict {
+        Err(error.context(loc))
+    } else {
+        warn!("{loc}: {error}");
+        Ok(())
+    }
+}
+
+/// Converts wiki links and ruby in `text`, which is at `offset` in `metadata.body`, into HTML.
+/// `None` if there is neither.
+fn render_text(
+    metadata: &ArticleMetadata,
+    text: &str,
+    offset: usize,
+) -> anyhow::Result<Option<String>> {
+    if !WIKI_LINK_PATTERN.is_match(text) {
+        return Ok(render_ruby(text));
+    }
+
+    let render_plain =
+        |text: &str| render_ruby(text).unwrap_or_else(|| html! { (text) }.into_string());
+    let mut rendered = String::new();
+    let mut last = 0;
+    for caps in WIKI_LINK_PATTERN.captures_iter(text) {
+        let whole = caps.get(0).unwrap();
+        rendered.push_str(&render_plain(&text[last..whole.start()]));
+        match render_wiki_link(&caps["target"], caps.name("label").map(|l| l.as_str())) {
+            Ok(link) => rendered.push_str(&link),
+            Err(e) => {
+                report_broken_link(metadata, offset + whole.start(), e)?;
+                rendered.push_str(&render_plain(whole.as_str()));
+            }
+        }
+        last = whole.end();
+    }
+    rendered.push_str(&render_plain(&text[last..]));
+
+    Ok(Some(rendered))
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{2E80}'..='\u{2FDF}' // CJK radicals
+        | '\u{3000}'..='\u{30FF}' // CJK symbols and punctuation, hiragana, katakana
+        | '\u{31F0}'..='\u{31FF}' // katakana phonetic extensions
+        | '\u{3400}'..='\u{4DBF}' // CJK unified ideographs extension A
+        | '\u{4E00}'..='\u{9FFF}' // CJK unified ideographs
+        | '\u{F900}'..='\u{FAFF}' // CJK compatibility ideographs
+        | '\u{FF00}'..='\u{FFEF}' // halfwidth and fullwidth forms
+        | '\u{20000}'..='\u{3FFFF}' // supplementary ideographic planes
+    )
+}
+
+/// Replaces `Event::SoftBreak`s following `mode`.
+/// This needs characters on both sides, so it cannot be done in `gen_parser_event_iterator`.
+pub(super) fn resolve_soft_breaks(events: &mut [(Event, usize)], mode: LineBreak) {
+    // the nearest character in the same paragraph, looking through inline tags
+    fn adjacent_char<'a>(
+        mut events: impl Iterator<Item = &'a Event<'a>>,
+        last: bool,
+    ) -> Option<char> {
+        events.find_map(|e| match e {
+            Event::Text(t) | Event::Code(t) => {
+                let c = if last {
+                    t.chars().last()
+                } else {
+                    t.chars().next()
+                };
+                Some(c)
+            }
+            Event::Start(Tag::Emphasis | Tag::Strong | Tag::Strikethrough | Tag::Link { .. })
+            | Event::End(
+                TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough | TagEnd::Link,
+            ) => None,
+            _ => Some(None),
+        })?
+    }
+
+    for i in 0..events.len() {
+        if events[i].0 != Event::SoftBreak {
+            continue;
+        }
+        events[i].0 = match mode {
+            LineBreak::Hard => Event::HardBreak,
+            LineBreak::Space => Event::SoftBreak,
+            LineBreak::Cjk => {
+                let prev = adjacent_char(events[..i].iter().map(|(e, _)| e).rev(), true);
+                let next = adjacent_char(events[i + 1..].iter().map(|(e, _)| e), false);
+                if prev.is_some_and(is_cjk) && next.is_some_and(is_cjk) {
+                    Event::Text("".into())
+                } else {
+                    Event::SoftBreak
+                }
+            }
+        };
+    }
+}
+
+/// Converts events paired with their offsets in `metadata.body`.
+/// Headings are demoted by `heading_offset`, and `headings` receives `(level, id, title)` of each of them.
+pub(super) fn gen_parser_event_iterator<'a>(
+    metadata: &'a ArticleMetadata,
+    heading_offset: usize,
+    headings: &'a mut Headings,
+    slugger: &'a mut Slugger,
+) -> Box<dyn FnMut((Event<'a>, usize)) -> anyhow::Result<Event<'a>> + 'a> {
     let ctx = Context::instance();
     let mut ogp_replacing = false;
+    // (attributes, content, offset) of the fenced code block being read
+    let mut code_block: Option<(CodeBlockInfo, String, usize)> = None;
+    let mut heading: Option<PendingHeading> = None;
+    let mut image: Option<PendingImage> = None;
 
-    Box::new(move |event: Event| -> Event {
+    let mut convert = move |event: Event<'a>, offset: usize| -> anyhow::Result<Event<'a>> {
         // TODO: 数式とか?
         // debug!("{:?}", event);
         match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let info = match kind {
+                    CodeBlockKind::Fenced(info) => CodeBlockInfo::parse(&info)
+                        .with_context(|| format!("in code block info string {info:?}"))?,
+                    CodeBlockKind::Indented => CodeBlockInfo::default(),
+                };
+                code_block = Some((info, String::new(), offset));
+                Ok(Event::Text("".into()))
+            }
+            Event::Text(ref text) if code_block.is_some() => {
+                code_block.as_mut().unwrap().1.push_str(text);
+                Ok(Event::Text("".into()))
+            }
+            Event::Start(Tag::Image {
+                dest_url, title, ..
+            }) if image.is_none() => {
+                image = Some(PendingImage::new(dest_url, title));
+                Ok(Event::Text("".into()))
+            }
+            ref e if image.is_some() => {
+                if !image.as_mut().unwrap().read(e) {
+                    return Ok(Event::Text("".into()));
+                }
+                let image = image.take().unwrap();
+                Ok(Event::Html(render_image(metadata, &image)?.into()))
+            }
+            Event::End(TagEnd::Image) => bail!("Unexpected end of an image"),
+            Event::Start(Tag::BlockQuote(Some(kind))) => {
+                Ok(Event::Html(render_admonition_start(kind).into()))
+            }
+            Event::End(TagEnd::BlockQuote(Some(_))) => Ok(Event::Html("</aside>\n".into())),
+            Event::Text(ref text) if !ogp_replacing => match render_text(metadata, text, offset)? {
+                Some(html) => Ok(Event::Html(html.into())),
+                None => Ok(event),
+            },
+            Event::End(TagEnd::CodeBlock) => {
+                let (info, mut code, start) = code_block.take().unwrap_or_default();
+                if info.include.is_some() {
+                    if !code.trim().is_empty() {
+                        warn!("Content of code block is ignored because `include` is given");
+                    }
+                    code = read_include(&metadata.relpath, &info)?;
+                }
+                if is_diagram(&info.lang) {
+                    let diagram = render_diagram(&info.lang, &code, info.title.as_deref())
+                        .with_context(|| format!("in diagram at {}", location(metadata, start)))?;
+                    return Ok(Event::Html(diagram.into()));
+                }
+                Ok(Event::Html(render_code_block(&info, &code).into()))
+            }
             Event::Start(Tag::Link {
                 link_type: LinkType::Autolink,
                 dest_url: ref url,
                 ..
-            }) => {
-                // fetch OGP info
-                // 内部リンクの場合自動的に(index).htmlを付与する、とかあった方が便利そうだな
-                {
-                    debug!("Getting cache of {url}...");
-                    let cache = ctx.opengraph_cache.lock().unwrap();
-                    if let Some(c) = cache.get(&url.to_string()) {
-                        debug!("done.");
-                        if *c != Value::Null {
-                            let mut will_render_card = true;
-                            let mut og = Opengraph::empty();
-                            if let Some(Value::String(og_type)) = c.get("type") {
-                                og.og_type = og_type.clone();
-                            } else {
-                                will_render_card = false;
-                                warn!("Invalid cache (type does not exist): {}", c);
-                            }
-                            if let Some(Value::String(og_title)) = c.get("title") {
-                                og.properties.insert("title".to_string(), og_title.clone());
-                            } else {
-                                will_render_card = false;
-                                warn!("Invalid cache (title does not exist): {}", c);
-                            }
-                            if let Some(Value::String(og_url)) = c.get("url") {
-                                og.properties.insert("url".to_string(), og_url.clone());
-                            } else {
-                                will_render_card = false;
-                                warn!("Invalid cache (url does not exist): {}", c);
-                            }
-                            if let Some(Value::String(og_thumb_url)) = c.get("thumb_url") {
-                                og.images = vec![OpengraphObject::new(og_thumb_url.clone())];
-                            } else {
-                                will_render_card = false;
-                                warn!(
-                                    "Invalid cache (thumbnail url(thumb_url) does not exist): {}",
-                                    c
-                                )
-                            }
-                            if let Some(Value::String(description)) = c.get("description") {
-                                og.properties
-                                    .insert("description".to_string(), description.clone());
-                            }
-
-                            if will_render_card {
-                                ogp_replacing = true;
-                                return Event::Html(render_card(url, &og).into());
-                            }
-                        } else {
-                            // nullの時returnするの忘れてたな……
-                            debug!("but there seemed to be no ogp info.");
-                            return event;
-                        }
-                    }
+            }) => match link_card(
+                url,
+                &ctx.opengraph_cache,
+                &ctx.config.ogp,
+                &ctx.thumbnail_dir,
+                &ctx.handlebars,
+            ) {
+                Some(card) => {
+                    ogp_replacing = true;
+                    Ok(Event::Html(card.into()))
                 }
-
-                debug!("failed. fetching...");
-                // there is no cache: try to fetch
-                let mut options = WebpageOptions::default();
-                // Hint from https://qiita.com/JunkiHiroi/items/f03d4297e11ce5db172e: this may be useful even for other than twitter
-                options.useragent = "bot".to_string();
-
-                if let Ok(webpage) = Webpage::from_url(url, options) {
-                    std::thread::sleep(std::time::Duration::from_secs(10));
-                    debug!("done.");
-
-                    // OGP Requirements: title, type, url, image. So convert into card only if all of them exist
-                    let og = webpage.html.opengraph;
-                    if !og.og_type.is_empty()
-                        && og.properties.contains_key("title")
-                        && og.properties.contains_key("url")
-                        && !og.images.is_empty()
-                    {
-                        // caching.
-                        {
-                            let mut cache = ctx.opengraph_cache.lock().unwrap();
-                            cache.insert(
-                                url.to_string(),
-                                if og.properties.contains_key("description") {
-                                    json!({
-                                        "type": og.og_type,
-                                        "title": og.properties["title"],
-                                        "url": og.properties["url"],
-                                        "description": og.properties["description"],
-                                        "thumb_url": og.images[0].url
-                                    })
-                                } else {
-                                    json!({
-                                        "type": og.og_type,
-                                        "title": og.properties["title"],
-                                        "url": og.properties["url"],
-                                        "thumb_url": og.images[0].url
-                                    })
-                                },
-                            );
+                None => Ok(event),
+            },
+            Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) if link_type != LinkType::Email => {
+                let dest_url =
+                    match ArticleIndex::instance().resolve_link(&metadata.relpath, &dest_url) {
+                        Ok(Some(url)) => url.into(),
+                        Ok(None) => dest_url,
+                        Err(e) => {
+                            report_broken_link(metadata, offset, e)?;
+                            dest_url
                         }
-                        ogp_replacing = true;
-                        return Event::Html(render_card(url, &og).into());
-                    }
-                }
-                // no need to caching (because there is no ogp info, nor the webpage did not exist.)
-                {
-                    debug!("there was no ogp info.");
-                    let mut cache = ctx.opengraph_cache.lock().unwrap();
-                    cache.insert(url.to_string(), Value::Null);
-                }
-                event
+                    };
+                Ok(Event::Start(Tag::Link {
+                    link_type,
+                    dest_url,
+                    title,
+                    id,
+                }))
             }
             Event::End(TagEnd::Link) => {
                 if ogp_replacing {
                     ogp_replacing = false;
-                    Event::Text("".into())
+                    Ok(Event::Text("".into()))
                 } else {
-                    event
+                    Ok(event)
                 }
             }
-            Event::SoftBreak => Event::HardBreak,
             _ => {
                 if ogp_replacing {
-                    Event::Text("".into())
+                    Ok(Event::Text("".into()))
                 } else {
-                    event
+                    Ok(event)
                 }
             }
         }
-    })
+    };
+
+    Box::new(
+        move |(event, offset): (Event<'a>, usize)| -> anyhow::Result<Event<'a>> {
+            // headings are rendered at once to give them id from their content
+            match event {
+                Event::Start(Tag::Heading {
+                    level,
+                    id,
+                    classes,
+                    attrs,
+                }) => {
+                    heading = Some(PendingHeading {
+                        level: demote(level, heading_offset),
+                        id,
+                        classes,
+                        attrs,
+                        inner: vec![],
+                        text: String::new(),
+                    });
+                    Ok(Event::Text("".into()))
+                }
+                Event::End(TagEnd::Heading(_)) if heading.is_some() => {
+                    let h = heading.take().unwrap();
+                    let id = slugger.slug(&h.text, h.id.as_deref());
+                    headings.push((h.level as usize, id.clone(), h.text.clone()));
+                    Ok(Event::Html(
+                        render_heading(h, &id, ctx.config.heading.permalink).into(),
+                    ))
+                }
+                _ => {
+                    if let Some(ref mut h) = heading {
+                        match event {
+                            Event::Text(ref t) => h.text.push_str(&strip_ruby(t)),
+                            Event::Code(ref t) => h.text.push_str(t),
+                            _ => {}
+                        }
+                    }
+                    let event = convert(event, offset)?;
+                    match heading {
+                        Some(ref mut h) => {
+                            h.inner.push(event);
+                            Ok(Event::Text("".into()))
+                        }
+                        None => Ok(event),
+                    }
+                }
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generator::render_markdown, test_utils::init_context};
+    use pulldown_cmark::Parser;
+
+    fn render_with_line_break(markdown: &str, mode: LineBreak) -> String {
+        let mut events: Vec<_> = Parser::new(markdown)
+            .into_offset_iter()
+            .map(|(e, range)| (e, range.start))
+            .collect();
+        resolve_soft_breaks(&mut events, mode);
+        let mut html = String::new();
+        pulldown_cmark::html::push_html(&mut html, events.into_iter().map(|(e, _)| e));
+        html
+    }
+
+    #[test]
+    fn joins_lines_only_between_cjk_characters() {
+        let render = |markdown| render_with_line_break(markdown, LineBreak::Cjk);
+        assert_eq!(render("日本語の\n文章"), "<p>日本語の文章</p>\n");
+        assert_eq!(
+            render("**日本語**の\n*文章*"),
+            "<p><strong>日本語</strong>の<em>文章</em></p>\n"
+        );
+        assert_eq!(render("日本語\nEnglish"), "<p>日本語\nEnglish</p>\n");
+        assert_eq!(render("English\n日本語"), "<p>English\n日本語</p>\n");
+        assert_eq!(render("English\ntext"), "<p>English\ntext</p>\n");
+    }
+
+    /// Renders `body` as the whole of an article at `a.md`.
+    fn render(body: &str) -> anyhow::Result<String> {
+        init_context();
+        let mut metadata = ArticleMetadata::new(std::fs::metadata(".").unwrap());
+        metadata.source_relpath = "a.md".into();
+        metadata.body = body.to_string();
+        Ok(render_markdown(&metadata, &metadata.body, 0, &mut Slugger::default())?.0)
+    }
+
+    #[test]
+    fn locates_broken_diagram_by_its_own_offset() {
+        let error =
+            render("```text\ndigraph {\n```\n\n> quoted:\n>\n> ```dot\n> digraph {\n> ```\n")
+                .unwrap_err();
+        assert_eq!(error.to_string(), "in diagram at a.md:7:3");
+    }
+
+    #[test]
+    fn renders_admonitions_as_asides() {
+        assert_eq!(
+            render("> [!NOTE]\n> Some *notes*.\n").unwrap(),
+            "<aside class=\"admonition admonition-note\"><p class=\"admonition-title\">メモ</p>\n\
+             <p>Some <em>notes</em>.</p>\n</aside>\n"
+        );
+        assert!(render("> [!CAUTION]\n> x\n").unwrap().starts_with(
+            r#"<aside class="admonition admonition-caution"><p class="admonition-title">警告</p>"#
+        ));
+        // unknown kinds are plain blockquotes
+        let html = render("> [!UNKNOWN]\n> x\n").unwrap();
+        assert!(html.starts_with("<blockquote>\n<p>[!UNKNOWN]"), "{html}");
+    }
 }
@@ -0,0 +1,195 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context as _};
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Theme, ThemeSet},
+    html::{
+        css_for_theme_with_class_style, line_tokens_to_classed_spans,
+        styled_line_to_highlighted_html, ClassStyle, IncludeBackground,
+    },
+    parsing::{ParseState, ScopeStack, ScopeStackOp, SyntaxSet},
+    util::LinesWithEndings,
+};
+
+use crate::config::{HighlightConfig, HighlightStyle};
+
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+/// Build-time syntax highlighter for fenced code blocks.
+pub(crate) struct Highlighter {
+    syntax_set: SyntaxSet,
+    theme: Theme,
+    style: HighlightStyle,
+}
+
+impl std::fmt::Debug for Highlighter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Highlighter")
+            .field("theme", &self.theme.name)
+            .field("style", &self.style)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Highlighter {
+    pub fn new(config: &HighlightConfig) -> anyhow::Result<Self> {
+        let theme = if config.theme.ends_with(".tmTheme") {
+            ThemeSet::get_theme(Path::new(&config.theme))
+                .with_context(|| format!("while loading theme {:?}", config.theme))?
+        } else {
+            ThemeSet::load_defaults()
+                .themes
+                .remove(&config.theme)
+                .ok_or_else(|| anyhow!("Unknown highlight theme: {}", config.theme))?
+        };
+
+        Ok(Self {
+            syntax_set: SyntaxSet::load_defaults_newlines(),
+            theme,
+            style: config.style,
+        })
+    }
+
+    /// Whether [`Highlighter::stylesheet`] returns a stylesheet, which pages need to link to.
+    pub fn has_stylesheet(&self) -> bool {
+        self.style == HighlightStyle::Class
+    }
+
+    /// Stylesheet for the classes emitted by [`Highlighter::highlight_lines`].
+    /// `None` if highlighting uses inline styles.
+    pub fn stylesheet(&self) -> anyhow::Result<Option<String>> {
+        match self.style {
+            HighlightStyle::Class => Ok(Some(css_for_theme_with_class_style(
+                &self.theme,
+                CLASS_STYLE,
+            )?)),
+            HighlightStyle::Inline => Ok(None),
+        }
+    }
+
+    /// Inline style for the `<pre>` element wrapping highlighted code, if any.
+    pub fn pre_style(&self) -> Option<String> {
+        match (self.style, self.theme.settings.background) {
+            (HighlightStyle::Inline, Some(c)) => Some(format!(
+                "background-color:#{:02x}{:02x}{:02x};",
+                c.r, c.g, c.b
+            )),
+            _ => None,
+        }
+    }
+
+    /// Highlights `code` as `lang` (a name or a file extension).
+    /// Each line of the result has balanced `<span>`s and no line ending, so lines can be wrapped separately.
+    /// Returns `None` for unknown languages.
+    pub fn highlight_lines(&self, lang: &str, code: &str) -> anyhow::Result<Option<Vec<String>>> {
+        let Some(syntax) = self.syntax_set.find_syntax_by_token(lang) else {
+            return Ok(None);
+        };
+
+        let mut lines = vec![];
+        match self.style {
+            HighlightStyle::Class => {
+                let mut parse_state = ParseState::new(syntax);
+                let mut scope_stack = ScopeStack::new();
+                for line in LinesWithEndings::from(code) {
+                    // reopen spans left open by the previous line
+                    let reopen_ops: Vec<_> = scope_stack
+                        .as_slice()
+                        .iter()
+                        .map(|scope| (0, ScopeStackOp::Push(*scope)))
+                        .collect();
+                    let (mut html, _) = line_tokens_to_classed_spans(
+                        "",
+                        &reopen_ops,
+                        CLASS_STYLE,
+                        &mut ScopeStack::new(),
+                    )?;
+
+                    let ops = parse_state.parse_line(line, &self.syntax_set)?;
+                    let (formatted, _) =
+                        line_tokens_to_classed_spans(line, &ops, CLASS_STYLE, &mut scope_stack)?;
+                    html.push_str(&formatted);
+                    for _ in 0..scope_stack.len() {
+                        html.push_str("</span>");
+                    }
+                    lines.push(html.replace(['\r', '\n'], ""));
+                }
+            }
+            HighlightStyle::Inline => {
+                let mut highlighter = HighlightLines::new(syntax, &self.theme);
+                for line in LinesWithEndings::from(code) {
+                    let regions = highlighter.highlight_line(line, &self.syntax_set)?;
+                    lines.push(
+                        styled_line_to_highlighted_html(&regions, IncludeBackground::No)?
+                            .replace(['\r', '\n'], ""),
+                    );
+                }
+            }
+        }
+
+        Ok(Some(lines))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn highlighter(style: HighlightStyle) -> Highlighter {
+        Highlighter::new(&HighlightConfig {
+            style,
+            ..HighlightConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn closes_spans_on_each_line() {
+        let highlighter = highlighter(HighlightStyle::Class);
+        let lines = highlighter
+            .highlight_lines("rust", "/* a\nb */\nfn main() {}\n")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(lines.len(), 3);
+        for line in lines.iter() {
+            assert_eq!(
+                line.matches("<span").count(),
+                line.matches("</span>").count()
+            );
+        }
+        // the comment continues on the second line
+        assert!(lines[1].starts_with(r#"<span class="hl-source hl-rust">"#));
+        assert!(lines[1].contains("hl-comment"));
+        assert!(highlighter
+            .stylesheet()
+            .unwrap()
+            .unwrap()
+            .contains(".hl-comment"));
+        assert_eq!(highlighter.pre_style(), None);
+    }
+
+    #[test]
+    fn highlights_with_inline_styles() {
+        let highlighter = highlighter(HighlightStyle::Inline);
+        let lines = highlighter
+            .highlight_lines("rs", "fn main() {}\n")
+            .unwrap()
+            .unwrap();
+
+        assert!(lines[0].contains(r#"<span style="#));
+        assert!(!highlighter.has_stylesheet());
+        assert_eq!(highlighter.stylesheet().unwrap(), None);
+        assert!(highlighter.pre_style().is_some());
+    }
+
+    #[test]
+    fn leaves_unknown_languages() {
+        let highlighter = highlighter(HighlightStyle::Class);
+        assert_eq!(
+            highlighter.highlight_lines("nonexistent", "a\n").unwrap(),
+            None
+        );
+    }
+}
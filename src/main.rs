@@ -1,13 +1,22 @@
 pub(crate) use crate::{context::Context, generator::generate};
 use anyhow::bail;
-use cache::{load_cache, save_cache};
-use clap::{command, Arg};
-use std::{path::PathBuf, sync::Mutex};
+use cache::OgpCache;
+use clap::{command, Arg, ArgAction};
+use config::load_config;
+use highlighter::Highlighter;
+use ogp::HttpFetcher;
+use std::{path::PathBuf, sync::Mutex, time::Duration};
 
 mod cache;
+mod cache_command;
+mod config;
 mod context;
 mod generator;
+mod highlighter;
+mod ogp;
 mod renderer;
+#[cfg(test)]
+mod test_utils;
 
 fn main() -> anyhow::Result<()> {
     env_logger::init();
@@ -30,9 +39,30 @@ fn main() -> anyhow::Result<()> {
                 .help("Directory of template")
                 .value_parser(clap::value_parser!(PathBuf))
                 .default_value("template"),
+            Arg::new("config")
+                .long("config")
+                .help("Path of config file. Defaults are used if it does not exist.")
+                .value_parser(clap::value_parser!(PathBuf))
+                .default_value("blogen.toml")
+                .global(true),
+            Arg::new("offline")
+                .long("offline")
+                .help("Do not fetch OGP info. Links not in the cache are shown as plain links.")
+                .action(ArgAction::SetTrue),
         ])
+        .subcommand(cache_command::command())
+        .args_conflicts_with_subcommands(true)
         .get_matches();
 
+    let config_file_path: &PathBuf = matches.get_one("config").unwrap();
+    let mut config = load_config(config_file_path)?;
+    let cache = OgpCache::new(config.ogp.storage);
+    let thumbnail_dir = PathBuf::from("ogp-thumbnails");
+
+    if let Some(("cache", matches)) = matches.subcommand() {
+        return cache_command::run(matches, &config, &cache, &thumbnail_dir);
+    }
+
     let article_dir: &PathBuf = matches.get_one("article_dir").unwrap();
     if !article_dir.exists() || !article_dir.is_dir() {
         bail!("article_dir must be a directory.");
@@ -51,26 +81,35 @@ fn main() -> anyhow::Result<()> {
         bail!("template_dir must be a directory.")
     }
 
+    config.ogp.offline |= matches.get_flag("offline");
+
     let handlebars = renderer::generate_renderer(template_dir)?;
+    let (shortcodes, shortcode_args) = renderer::generate_shortcode_renderer(template_dir)?;
+    let highlighter = Highlighter::new(&config.highlight)?;
 
-    let cache_file_path = PathBuf::from("cache.json.zst");
-    Context::init(
-        article_dir.to_owned(),
-        out_dir.to_owned(),
-        public_dir.to_owned(),
-        std::env::var("BLOG_NAME").unwrap_or_default(),
-        std::env::var("BLOG_URL").unwrap_or_default(),
+    let timeout = Duration::from_secs(config.ogp.request_timeout);
+    Context::init(Context {
+        article_dir: article_dir.to_owned(),
+        out_dir: out_dir.to_owned(),
+        public_dir: public_dir.to_owned(),
+        blog_name: std::env::var("BLOG_NAME").unwrap_or_default(),
+        blog_url: std::env::var("BLOG_URL").unwrap_or_default(),
+        config,
         handlebars,
-        Mutex::new(load_cache(&cache_file_path)?),
-    );
+        shortcodes,
+        shortcode_args,
+        highlighter,
+        diagram_cache_dir: PathBuf::from("diagram-cache"),
+        image_cache_dir: PathBuf::from("image-cache"),
+        thumbnail_dir,
+        opengraph_cache: Mutex::new(cache.load()?),
+        ogp_fetcher: Box::new(HttpFetcher { timeout }),
+    });
 
     generate()?;
 
     // save cache
-    save_cache(
-        &cache_file_path,
-        &context::Context::instance().opengraph_cache.lock().unwrap(),
-    )?;
+    cache.save(&context::Context::instance().opengraph_cache.lock().unwrap())?;
 
     Ok(())
 }
@@ -0,0 +1,200 @@
+use std::{fmt::Debug, sync::LazyLock, time::Duration};
+
+use curl::easy::Easy;
+use regex::Regex;
+use url::Url;
+use webpage::{Webpage, WebpageOptions, HTML};
+
+/// Source of web pages to read Open Graph data from. Chosen when `Context` is constructed.
+pub(crate) trait OgpFetcher: Debug + Send + Sync {
+    /// Fetches and parses the page at `url`. An error means the page could not be fetched.
+    fn fetch(&self, url: &str) -> anyhow::Result<Page>;
+    /// Downloads the image at `url`, such as a thumbnail.
+    fn fetch_image(&self, url: &str) -> anyhow::Result<Vec<u8>>;
+}
+
+/// User agent of requests.
+/// Hint from https://qiita.com/JunkiHiroi/items/f03d4297e11ce5db172e: this may be useful even for other than twitter
+const USER_AGENT: &str = "bot";
+
+#[derive(Debug)]
+pub(crate) struct Page {
+    /// HTTP status code
+    pub status: u32,
+    /// URL after redirections
+    pub url: String,
+    pub html: HTML,
+    /// absolute URL of the icon declared by `<link rel="icon">`, which `html` does not have
+    pub icon: Option<String>,
+}
+
+static LINK_TAG_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)<link\s[^>]*>").unwrap());
+static ATTRIBUTE_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"([\w-]+)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#).unwrap());
+
+/// URL of the icon declared in `body` (such as `<link rel="shortcut icon" href="/favicon.png">`), resolved against `url`.
+fn declared_icon(body: &str, url: &str) -> Option<String> {
+    let base = Url::parse(url).ok()?;
+    LINK_TAG_PATTERN.find_iter(body).find_map(|tag| {
+        let mut rel = None;
+        let mut href = None;
+        for caps in ATTRIBUTE_PATTERN.captures_iter(tag.as_str()) {
+            let value = caps
+                .get(2)
+                .or_else(|| caps.get(3))
+                .or_else(|| caps.get(4))
+                .map_or("", |m| m.as_str());
+            match caps[1].to_ascii_lowercase().as_str() {
+                "rel" => rel = Some(value),
+                "href" => href = Some(value),
+                _ => {}
+            }
+        }
+        rel?.split_ascii_whitespace()
+            .any(|r| r.eq_ignore_ascii_case("icon"))
+            .then(|| base.join(href?).ok())
+            .flatten()
+            .map(String::from)
+    })
+}
+
+/// Fetches pages over HTTP.
+#[derive(Debug)]
+pub(crate) struct HttpFetcher {
+    /// timeout of each request
+    pub timeout: Duration,
+}
+
+impl OgpFetcher for HttpFetcher {
+    fn fetch(&self, url: &str) -> anyhow::Result<Page> {
+        let mut options = WebpageOptions::default();
+        options.useragent = USER_AGENT.to_string();
+        options.timeout = self.timeout;
+
+        let webpage = Webpage::from_url(url, options)?;
+        Ok(Page {
+            status: webpage.http.response_code,
+            icon: declared_icon(&webpage.http.body, &webpage.http.url),
+            url: webpage.http.url,
+            html: webpage.html,
+        })
+    }
+
+    fn fetch_image(&self, url: &str) -> anyhow::Result<Vec<u8>> {
+        let mut easy = Easy::new();
+        easy.url(url)?;
+        easy.useragent(USER_AGENT)?;
+        easy.timeout(self.timeout)?;
+        easy.follow_location(true)?;
+        easy.fail_on_error(true)?;
+
+        let mut body = vec![];
+        {
+            let mut transfer = easy.transfer();
+            transfer.write_function(|data| {
+                body.extend_from_slice(data);
+                Ok(data.len())
+            })?;
+            transfer.perform()?;
+        }
+        Ok(body)
+    }
+}
+
+/// Serves pages from HTML given in advance, without network access. URLs not given are treated as unreachable.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct StubFetcher {
+    /// status and HTML
+    pages: std::collections::HashMap<String, (u32, String)>,
+    images: std::collections::HashMap<String, Vec<u8>>,
+    /// URLs requested so far, in order
+    pub requested: std::sync::Mutex<Vec<String>>,
+    /// when each of `requested` was requested
+    pub requested_at: std::sync::Mutex<Vec<std::time::Instant>>,
+}
+
+#[cfg(test)]
+impl StubFetcher {
+    pub fn with_page(self, url: &str, html: &str) -> Self {
+        self.with_status(url, 200, html)
+    }
+
+    pub fn with_status(mut self, url: &str, status: u32, html: &str) -> Self {
+        self.pages
+            .insert(url.to_string(), (status, html.to_string()));
+        self
+    }
+
+    pub fn with_image(mut self, url: &str, image: Vec<u8>) -> Self {
+        self.images.insert(url.to_string(), image);
+        self
+    }
+
+    fn record(&self, url: &str) {
+        let mut requested = self.requested.lock().unwrap();
+        requested.push(url.to_string());
+        self.requested_at
+            .lock()
+            .unwrap()
+            .push(std::time::Instant::now());
+    }
+
+    /// when `url` was first requested
+    pub fn first_requested_at(&self, url: &str) -> Option<std::time::Instant> {
+        let requested = self.requested.lock().unwrap();
+        let i = requested.iter().position(|r| r == url)?;
+        Some(self.requested_at.lock().unwrap()[i])
+    }
+}
+
+#[cfg(test)]
+impl OgpFetcher for StubFetcher {
+    fn fetch(&self, url: &str) -> anyhow::Result<Page> {
+        self.record(url);
+        let (status, html) = self
+            .pages
+            .get(url)
+            .ok_or_else(|| anyhow::anyhow!("{url} is not in the stub"))?;
+        Ok(Page {
+            status: *status,
+            url: url.to_string(),
+            html: HTML::from_string(html.clone(), Some(url.to_string()))?,
+            icon: declared_icon(html, url),
+        })
+    }
+
+    fn fetch_image(&self, url: &str) -> anyhow::Result<Vec<u8>> {
+        self.record(url);
+        self.images
+            .get(url)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("{url} is not in the stub"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_declared_icon() {
+        let body = r#"<head>
+            <link rel="stylesheet" href="/style.css">
+            <link href='icons/favicon.png' REL="shortcut icon" type="image/png">
+            <link rel=icon href=/other.ico>
+            </head>"#;
+        assert_eq!(
+            declared_icon(body, "https://example.com/docs/page").as_deref(),
+            Some("https://example.com/docs/icons/favicon.png")
+        );
+        assert_eq!(
+            declared_icon(
+                "<link rel=\"apple-touch-icon\" href=\"/a.png\">",
+                "https://example.com/"
+            ),
+            None
+        );
+    }
+}
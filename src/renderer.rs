@@ -1,8 +1,13 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
 
 use anyhow::Context;
 use handlebars::{handlebars_helper, Handlebars};
 use maud::html;
+use regex::Regex;
 
 handlebars_helper!(breadcrumbs: |path: PathBuf| {
     let mut current_path = PathBuf::from("/");
@@ -33,6 +38,8 @@ handlebars_helper!(slice: |lst: array, lower: usize, upper: usize| lst[lower..up
 
 pub(super) fn generate_renderer(template_dir: &Path) -> anyhow::Result<Handlebars<'static>> {
     let mut handlebars = Handlebars::new();
+    // indenting partials breaks the content of <pre>
+    handlebars.set_prevent_indent(true);
     handlebars.register_helper("breadcrumbs", Box::new(breadcrumbs));
     handlebars.register_helper("with_extension", Box::new(with_extension));
     handlebars.register_helper("slice", Box::new(slice));
@@ -51,6 +58,105 @@ pub(super) fn generate_renderer(template_dir: &Path) -> anyhow::Result<Handlebar
         "layout",
         std::fs::read_to_string(template_dir.join("layout.hbs")).context("header.hbs")?,
     )?;
+    handlebars.register_partial(
+        "toc",
+        std::fs::read_to_string(template_dir.join("toc.hbs")).context("toc.hbs")?,
+    )?;
+    register_card_templates(&mut handlebars, template_dir)?;
 
     Ok(handlebars)
 }
+
+/// Card of links used if `og_card.hbs` does not exist in `template_dir`.
+const DEFAULT_OG_CARD: &str = include_str!("../template/og_card.hbs");
+
+/// Registers `og_card.hbs` and `og_card_<og:type>.hbs` in `template_dir` for link cards.
+/// They can also be used as partials.
+pub(super) fn register_card_templates(
+    handlebars: &mut Handlebars,
+    template_dir: &Path,
+) -> anyhow::Result<()> {
+    handlebars.register_template_string("og_card", DEFAULT_OG_CARD)?;
+    if !template_dir.is_dir() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(template_dir)? {
+        let path = entry?.path();
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        if path.extension().is_some_and(|ext| ext == "hbs")
+            && (name == "og_card" || name.starts_with("og_card_"))
+        {
+            handlebars
+                .register_template_file(&name, &path)
+                .with_context(|| format!("{path:?}"))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// `{{!-- args: src alt? caption? --}}` at the beginning of a shortcode template
+static SHORTCODE_ARGS_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\{\{!--\s*args:(?<args>.*?)--\}\}").unwrap());
+
+/// Arguments a shortcode template declares. Optional ones are suffixed with `?`,
+/// and `inner` stands for the content between opening and closing tags.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct ShortcodeArgs {
+    pub required: Vec<String>,
+    pub optional: Vec<String>,
+}
+
+impl ShortcodeArgs {
+    /// `None` if `template` declares nothing, in which case any arguments are accepted.
+    fn parse(template: &str) -> Option<Self> {
+        let caps = SHORTCODE_ARGS_PATTERN.captures(template.trim_start())?;
+        let mut args = Self::default();
+        for arg in caps["args"].split_whitespace() {
+            match arg.strip_suffix('?') {
+                Some(name) => args.optional.push(name.to_string()),
+                None => args.required.push(arg.to_string()),
+            }
+        }
+        Some(args)
+    }
+
+    pub fn accepts(&self, name: &str) -> bool {
+        self.required
+            .iter()
+            .chain(self.optional.iter())
+            .any(|a| a == name)
+    }
+}
+
+/// Templates of shortcodes in `template_dir/shortcodes`, registered as `shortcodes/<name>`,
+/// and arguments declared by them.
+/// Strict mode is enabled so that a missing argument is an error even if it is not declared.
+pub(super) fn generate_shortcode_renderer(
+    template_dir: &Path,
+) -> anyhow::Result<(Handlebars<'static>, HashMap<String, ShortcodeArgs>)> {
+    let mut handlebars = Handlebars::new();
+    handlebars.set_strict_mode(true);
+    handlebars.set_prevent_indent(true);
+    let mut declared = HashMap::new();
+
+    let shortcode_dir = template_dir.join("shortcodes");
+    if !shortcode_dir.is_dir() {
+        return Ok((handlebars, declared));
+    }
+    for entry in std::fs::read_dir(&shortcode_dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "hbs") {
+            let name = format!("shortcodes/{}", path.file_stem().unwrap().to_string_lossy());
+            let template = std::fs::read_to_string(&path).with_context(|| format!("{path:?}"))?;
+            if let Some(args) = ShortcodeArgs::parse(&template) {
+                declared.insert(name.clone(), args);
+            }
+            handlebars
+                .register_template_string(&name, template)
+                .with_context(|| format!("{path:?}"))?;
+        }
+    }
+
+    Ok((handlebars, declared))
+}
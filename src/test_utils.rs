@@ -0,0 +1,96 @@
+//! Helpers shared by tests of several modules.
+
+use std::{
+    path::PathBuf,
+    sync::{Mutex, Once},
+};
+
+use handlebars::Handlebars;
+
+use crate::{
+    cache::{CacheEntry, FetchResult, OgpEntries},
+    config::{Config, OgpConfig},
+    context::Context,
+    highlighter::Highlighter,
+    ogp::StubFetcher,
+    renderer::generate_shortcode_renderer,
+};
+
+/// Thumbnail directory for tests which download nothing, since thumbnails are disabled or not in the stub
+pub(crate) const NO_THUMBNAILS: &str = "/nonexistent";
+
+/// An empty directory for a test. `name` must be unique among tests, which run in parallel.
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("blogen-test-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// OGP settings which request only pages, without waiting between them
+pub(crate) fn ogp_config() -> OgpConfig {
+    OgpConfig {
+        per_host_interval: 0,
+        thumbnails: false,
+        ..OgpConfig::default()
+    }
+}
+
+/// A cache entry of a page which returned 404
+pub(crate) fn failure(fetched_at: Option<&str>) -> CacheEntry {
+    CacheEntry {
+        fetched_at: fetched_at.map(|t| t.parse().unwrap()),
+        status: Some(404),
+        result: FetchResult::Error("HTTP status 404".to_string()),
+    }
+}
+
+/// A blank PNG image
+pub(crate) fn png(width: u32, height: u32) -> Vec<u8> {
+    let mut png = vec![];
+    image::DynamicImage::new_rgb8(width, height)
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    png
+}
+
+/// Initializes `Context` with the default settings, for code which reads it. It is shared by all tests.
+/// Shortcodes `greet`, which takes `name` and optionally `greeting`, and `box`, which wraps its content, are available.
+pub(crate) fn init_context() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let config = Config::default();
+        let dir = temp_dir("context");
+        let shortcode_dir = dir.join("template/shortcodes");
+        std::fs::create_dir_all(&shortcode_dir).unwrap();
+        std::fs::write(
+            shortcode_dir.join("greet.hbs"),
+            "{{!-- args: name greeting? --}}<p>{{#if greeting}}{{greeting}}{{else}}Hello{{/if}}, {{name}}!</p>",
+        )
+        .unwrap();
+        std::fs::write(
+            shortcode_dir.join("box.hbs"),
+            "{{!-- args: inner --}}<div>{{{inner}}}</div>",
+        )
+        .unwrap();
+        let (shortcodes, shortcode_args) =
+            generate_shortcode_renderer(&dir.join("template")).unwrap();
+        Context::init(Context {
+            article_dir: dir.join("posts"),
+            out_dir: dir.join("out"),
+            public_dir: dir.join("public"),
+            blog_name: String::new(),
+            blog_url: String::new(),
+            highlighter: Highlighter::new(&config.highlight).unwrap(),
+            config,
+            handlebars: Handlebars::new(),
+            shortcodes,
+            shortcode_args,
+            diagram_cache_dir: dir.join("diagram-cache"),
+            image_cache_dir: dir.join("image-cache"),
+            thumbnail_dir: dir.join("ogp-thumbnails"),
+            opengraph_cache: Mutex::new(OgpEntries::new()),
+            ogp_fetcher: Box::new(StubFetcher::default()),
+        });
+    });
+}